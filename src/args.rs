@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -14,4 +15,73 @@ pub struct Args {
     // Print additionals debug informations
     #[clap(short, long)]
     pub debug: bool,
+
+    /// Filter rules file (TOML or YAML), extending or replacing the built-in filters
+    #[clap(long, value_parser, env = "MORBO_FILTERS")]
+    pub filters: Option<PathBuf>,
+
+    /// Interval, in seconds, between two checks for changes of the filter rules file
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5,
+        env = "MORBO_FILTERS_WATCH_INTERVAL"
+    )]
+    pub filters_watch_interval: u64,
+
+    /// Projects file (TOML or YAML), each project receiving its reports on /_/csp-reports/{project}
+    #[clap(long, value_parser, env = "MORBO_PROJECTS")]
+    pub projects: Option<PathBuf>,
+
+    /// Number of times a channel retries to send a report before giving up
+    #[clap(long, value_parser, default_value_t = 5, env = "MORBO_RETRIES")]
+    pub retries: u32,
+
+    /// Delay, in milliseconds, before the first retry, doubled on each retry
+    #[clap(long, value_parser, default_value_t = 1000, env = "MORBO_RETRY_DELAY")]
+    pub retry_delay: u64,
+
+    /// Maximum delay, in seconds, between two retries
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 300,
+        env = "MORBO_RETRY_MAX_DELAY"
+    )]
+    pub retry_max_delay: u64,
+
+    /// Outbox directory, where reports are stored until every channel sent them
+    #[clap(long, value_parser, env = "MORBO_OUTBOX")]
+    pub outbox: Option<PathBuf>,
+
+    /// Window, in seconds, during which the duplicates of a report are suppressed, and counted
+    #[clap(long, value_parser, env = "MORBO_DEDUP_WINDOW")]
+    pub dedup_window: Option<u64>,
+
+    /// Check that every channel can reach its service (SMTP server, Sentry) before starting
+    #[clap(long, env = "MORBO_CHECK_CHANNELS")]
+    pub check_channels: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect the outbox
+    #[clap(subcommand)]
+    Outbox(OutboxCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OutboxCommand {
+    /// Show how far each channel is in the outbox
+    Status,
+
+    /// Print the reports given up by the channels, as JSON lines
+    DeadLetters {
+        /// Only print the reports given up by this queue (channel, or project.channel)
+        #[clap(value_parser)]
+        queue: Option<String>,
+    },
 }
@@ -0,0 +1,138 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::channel::chat::{truncate, ChatMessage, ChatSettings, ChatWebhook};
+use crate::channel::support::EnvReader;
+use crate::channel::Channel;
+use crate::csp::received_report::ReceivedReport;
+
+/// Maximum length of the title of an embed
+const MAX_TITLE_LENGTH: usize = 256;
+
+/// Discord channel
+#[derive(Clone)]
+pub struct Discord {
+    pub webhook: ChatWebhook,
+
+    /// Name of the messages, instead of the one of the webhook
+    pub username: Option<String>,
+}
+
+impl Discord {
+    /// The embed payload of a message
+    fn payload(&self, report: &ReceivedReport, message: &ChatMessage) -> Value {
+        let fields: Vec<Value> = message
+            .fields
+            .iter()
+            .map(|field| {
+                json!({
+                    "name": field.name,
+                    "value": field.value,
+                    "inline": field.value.len() <= 40,
+                })
+            })
+            .collect();
+
+        let mut embed = json!({
+            "title": truncate(&message.title, MAX_TITLE_LENGTH - 1),
+            "color": message.severity.color(),
+            "fields": fields,
+            "timestamp": report.received_at.to_rfc3339(),
+        });
+        if let Some(link) = &message.link {
+            embed["url"] = json!(link);
+        }
+        if let Some(note) = message.suppressed_note() {
+            embed["footer"] = json!({ "text": note });
+        }
+
+        let mut payload = json!({
+            "embeds": [embed],
+            // Reports are written by browsers, they must not ping anyone
+            "allowed_mentions": { "parse": [] },
+        });
+        if let Some(username) = &self.username {
+            payload["username"] = json!(username);
+        }
+
+        payload
+    }
+}
+
+#[async_trait]
+impl Channel for Discord {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let settings = ChatSettings::from_env(&mut env, "MORBO_DISCORD");
+        let username = env.optional("MORBO_DISCORD_USERNAME");
+
+        env.finish()?;
+
+        Ok(Discord {
+            webhook: settings.webhook()?,
+            username,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        self.webhook.check().await
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        self.webhook
+            .post(report, |message| self.payload(report, message))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chat::{RateLimit, Severity};
+    use crate::csp::csp_report_content::CspReportContent;
+    use reqwest::Client;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn it_builds_embeds() {
+        let discord = Discord {
+            webhook: ChatWebhook {
+                url: Url::parse("https://discord.com/api/webhooks/1/x").unwrap(),
+                rate_limit: Arc::new(RateLimit::new(0, Duration::from_secs(60))),
+                client: Client::new(),
+            },
+            username: Some(String::from("morbo")),
+        };
+        let mut csp_report = CspReportContent::default(
+            &format!("https://{}.example.com/x.js", "a".repeat(300)),
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "img-src",
+        );
+        csp_report.disposition = Some(String::from("report"));
+        let report = ReceivedReport::new(csp_report);
+        let payload = discord.payload(&report, &ChatMessage::new(&report, 0));
+
+        assert_eq!("morbo", payload["username"]);
+        let embed = &payload["embeds"][0];
+        assert_eq!(
+            MAX_TITLE_LENGTH,
+            embed["title"].as_str().unwrap().chars().count()
+        );
+        assert_eq!(Severity::Report.color(), embed["color"]);
+        assert_eq!("https://shop.example.org/cart", embed["url"]);
+        assert_eq!("Directive", embed["fields"][0]["name"]);
+        assert_eq!(true, embed["fields"][0]["inline"]);
+        assert_eq!(false, embed["fields"][1]["inline"]);
+        assert!(embed.get("footer").is_none());
+    }
+}
@@ -0,0 +1,409 @@
+// The chat feature alone enables no channel
+#![cfg_attr(
+    not(any(feature = "discord", feature = "slack", feature = "teams")),
+    allow(dead_code)
+)]
+
+#[cfg(feature = "discord")]
+pub mod discord;
+
+#[cfg(feature = "slack")]
+pub mod slack;
+
+#[cfg(feature = "teams")]
+pub mod teams;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Error};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde::Serialize;
+use tracing::debug;
+use url::Url;
+
+use crate::channel::http::check_status;
+use crate::channel::support::{check_connection, EnvReader};
+use crate::csp::condition::host;
+use crate::csp::received_report::ReceivedReport;
+
+/// Maximum length of a field value, longer ones are cut
+const MAX_VALUE_LENGTH: usize = 300;
+
+/// The window of the rate limit, which caps the messages per minute
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How serious a violation is, from its disposition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Blocked by the browser
+    Enforce,
+    /// Only reported by the browser
+    Report,
+    /// Without disposition
+    Unknown,
+}
+
+impl Severity {
+    /// The colour of the messages, as RGB
+    #[cfg_attr(not(any(feature = "discord", feature = "slack")), allow(dead_code))]
+    pub fn color(self) -> u32 {
+        match self {
+            Severity::Enforce => 0xd0_00_00,
+            Severity::Report => 0xda_a0_38,
+            Severity::Unknown => 0x80_80_80,
+        }
+    }
+}
+
+/// A field of a chat message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatField {
+    pub name: &'static str,
+    pub value: String,
+}
+
+/// A report, as posted in a chat room. Each chat channel turns it into the
+/// payload of its service.
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub title: String,
+
+    /// The document of the violation, when it can be opened in a browser
+    pub link: Option<String>,
+    pub severity: Severity,
+    pub fields: Vec<ChatField>,
+
+    /// The number of reports not posted because of the rate limit since
+    /// the previous message
+    pub suppressed: u64,
+}
+
+/// The value, cut to a length a chat message can display
+pub fn truncate(value: &str, max: usize) -> String {
+    match value.char_indices().nth(max) {
+        Some((index, _)) => format!("{}…", &value[..index]),
+        None => value.to_string(),
+    }
+}
+
+impl ChatMessage {
+    pub fn new(report: &ReceivedReport, suppressed: u64) -> Self {
+        let csp_report = &report.csp_report;
+        let directive = csp_report
+            .effective_directive
+            .as_deref()
+            .unwrap_or(&csp_report.violated_directive);
+        let blocked =
+            host(&csp_report.blocked_uri).unwrap_or_else(|| csp_report.blocked_uri.clone());
+
+        let mut title = format!("CSP violation: {} blocked {}", directive, blocked);
+        if let Some(document_host) = host(&csp_report.document_uri) {
+            title.push_str(&format!(" on {}", document_host));
+        }
+
+        let severity = match csp_report.disposition.as_deref() {
+            Some("enforce") => Severity::Enforce,
+            Some("report") => Severity::Report,
+            _ => Severity::Unknown,
+        };
+
+        let mut fields = vec![];
+        let mut add = |name, value: Option<&str>| {
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                fields.push(ChatField {
+                    name,
+                    value: truncate(value, MAX_VALUE_LENGTH),
+                });
+            }
+        };
+        add("Directive", Some(directive));
+        add("Blocked URI", Some(&csp_report.blocked_uri));
+        add("Document URI", Some(&csp_report.document_uri));
+        add("Disposition", csp_report.disposition.as_deref());
+        add("Location", csp_report.location().as_deref());
+        if report.duplicates > 0 {
+            let occurrences = format!("{} times", report.duplicates + 1);
+            add("Occurrences", Some(&occurrences));
+        }
+        add("Project", report.project.as_deref());
+
+        ChatMessage {
+            title,
+            link: Url::parse(&csp_report.document_uri)
+                .ok()
+                .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+                .map(String::from),
+            severity,
+            fields,
+            suppressed,
+        }
+    }
+
+    /// The line telling how many reports were not posted, if any
+    pub fn suppressed_note(&self) -> Option<String> {
+        match self.suppressed {
+            0 => None,
+            1 => Some(String::from(
+                "1 more report was not posted because of the rate limit",
+            )),
+            n => Some(format!(
+                "{} more reports were not posted because of the rate limit",
+                n
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimitState {
+    window_start: Instant,
+    posted: u32,
+    suppressed: u64,
+}
+
+/// Caps the number of messages posted per window, so a violation storm
+/// does not flood the room. The reports over the cap are counted, and the
+/// count is posted with the next message.
+#[derive(Debug)]
+pub struct RateLimit {
+    /// Maximum number of messages per window, 0 meaning no limit
+    pub max: u32,
+    pub window: Duration,
+    state: Mutex<RateLimitState>,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, window: Duration) -> Self {
+        RateLimit {
+            max,
+            window,
+            state: Mutex::new(RateLimitState {
+                window_start: Instant::now(),
+                posted: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Take a slot for a message, returning the number of reports
+    /// suppressed since the previous message, or `None` when the limit is
+    /// reached and the report must not be posted
+    pub fn acquire(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        if now.duration_since(state.window_start) >= self.window {
+            state.window_start = now;
+            state.posted = 0;
+        }
+
+        if self.max > 0 && state.posted >= self.max {
+            state.suppressed += 1;
+            return None;
+        }
+
+        state.posted += 1;
+        Some(std::mem::take(&mut state.suppressed))
+    }
+
+    /// Give back the slot of a message which could not be posted, and put
+    /// back its count of suppressed reports
+    pub fn release(&self, suppressed: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.posted = state.posted.saturating_sub(1);
+        state.suppressed += suppressed;
+    }
+}
+
+/// The settings shared by the chat channels, read from the
+/// `<PREFIX>_WEBHOOK_URL`, `<PREFIX>_RATE_LIMIT` and `<PREFIX>_TIMEOUT`
+/// variables
+#[derive(Debug, Clone)]
+pub struct ChatSettings {
+    pub webhook_url: Url,
+    pub rate_limit: u32,
+    pub timeout: Duration,
+}
+
+/// Parse the URL of an incoming webhook
+pub fn webhook_url(url: &str) -> Result<Url, Error> {
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(parsed),
+        Ok(_) => bail!("expected an http(s) URL"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl ChatSettings {
+    pub fn from_env(env: &mut EnvReader, prefix: &str) -> Self {
+        let name = format!("{}_WEBHOOK_URL", prefix);
+        let url = env.required(&name);
+        let webhook_url = webhook_url(&url).unwrap_or_else(|e| {
+            // A missing URL is already reported
+            if !url.is_empty() {
+                env.error(format!("{} is invalid: {:#}", name, e));
+            }
+            Url::parse("http://localhost/").expect("Invalid placeholder URL")
+        });
+
+        ChatSettings {
+            webhook_url,
+            rate_limit: env
+                .parse_optional(&format!("{}_RATE_LIMIT", prefix))
+                .unwrap_or(20),
+            timeout: Duration::from_secs(
+                env.parse_optional(&format!("{}_TIMEOUT", prefix))
+                    .unwrap_or(10),
+            ),
+        }
+    }
+
+    /// The webhook posting the messages
+    pub fn webhook(&self) -> Result<ChatWebhook, Error> {
+        let client = Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .context("Unable to create the HTTP client")?;
+
+        Ok(ChatWebhook {
+            url: self.webhook_url.clone(),
+            rate_limit: Arc::new(RateLimit::new(self.rate_limit, RATE_LIMIT_WINDOW)),
+            client,
+        })
+    }
+}
+
+/// An incoming webhook of a chat service, posting messages within the rate
+/// limit
+#[derive(Clone)]
+pub struct ChatWebhook {
+    pub url: Url,
+    rate_limit: Arc<RateLimit>,
+    client: Client,
+}
+
+impl ChatWebhook {
+    /// Check that the host of the webhook can be reached
+    pub async fn check(&self) -> Result<(), Error> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+
+        check_connection(host, port).await
+    }
+
+    /// Post the message of a report, built by the given function, unless
+    /// the rate limit is reached
+    pub async fn post<T, F>(&self, report: &ReceivedReport, payload: F) -> Result<(), Error>
+    where
+        T: Serialize,
+        F: FnOnce(&ChatMessage) -> T,
+    {
+        let suppressed = match self.rate_limit.acquire() {
+            Some(suppressed) => suppressed,
+            None => {
+                debug!("Rate limit reached, report not posted");
+                return Ok(());
+            }
+        };
+
+        let body = serde_json::to_vec(&payload(&ChatMessage::new(report, suppressed)))?;
+        let posted = match self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(response) => check_status(self.url.as_str(), response.status()),
+            Err(e) => {
+                Err(Error::new(e).context(format!("Unable to post the message to {}", self.url)))
+            }
+        };
+
+        // A failed post does not count against the limit, so its retries
+        // can be posted, and the suppressed reports are mentioned in the
+        // next message instead
+        if posted.is_err() {
+            self.rate_limit.release(suppressed);
+        }
+
+        posted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    #[test]
+    fn it_formats_reports() {
+        let mut csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src 'self'",
+        );
+        csp_report.effective_directive = Some(String::from("script-src-elem"));
+        csp_report.disposition = Some(String::from("report"));
+        let mut report = ReceivedReport::new(csp_report);
+        report.duplicates = 2;
+
+        let message = ChatMessage::new(&report, 0);
+        assert_eq!(
+            "CSP violation: script-src-elem blocked evil.example.com on shop.example.org",
+            message.title
+        );
+        assert_eq!(
+            Some("https://shop.example.org/cart"),
+            message.link.as_deref()
+        );
+        assert_eq!(Severity::Report, message.severity);
+        assert_eq!(
+            vec![
+                "Directive",
+                "Blocked URI",
+                "Document URI",
+                "Disposition",
+                "Occurrences"
+            ],
+            message
+                .fields
+                .iter()
+                .map(|field| field.name)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(None, message.suppressed_note());
+
+        let truncated = truncate(&"é".repeat(1000), MAX_VALUE_LENGTH);
+        assert_eq!(MAX_VALUE_LENGTH + 1, truncated.chars().count());
+        assert!(truncated.ends_with('…'));
+    }
+
+    #[test]
+    fn it_caps_the_messages_per_window() {
+        let rate_limit = RateLimit::new(2, Duration::from_millis(50));
+
+        assert_eq!(Some(0), rate_limit.acquire());
+        assert_eq!(Some(0), rate_limit.acquire());
+        assert_eq!(None, rate_limit.acquire());
+        assert_eq!(None, rate_limit.acquire());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(Some(2), rate_limit.acquire());
+        assert_eq!(Some(0), rate_limit.acquire());
+        // The slot of a failed message is given back
+        rate_limit.release(2);
+        assert_eq!(Some(2), rate_limit.acquire());
+        assert_eq!(None, rate_limit.acquire());
+
+        let unlimited = RateLimit::new(0, Duration::from_secs(60));
+        for _ in 0..100 {
+            assert_eq!(Some(0), unlimited.acquire());
+        }
+    }
+}
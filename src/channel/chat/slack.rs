@@ -0,0 +1,320 @@
+use std::str::FromStr;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::channel::chat::{ChatMessage, ChatSettings, ChatWebhook};
+use crate::channel::support::EnvReader;
+use crate::channel::Channel;
+use crate::csp::received_report::ReceivedReport;
+
+/// The service behind the incoming webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlackFlavor {
+    /// Block Kit messages
+    Slack,
+    /// Slack attachments, Mattermost not supporting Block Kit
+    Mattermost,
+}
+
+impl FromStr for SlackFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "slack" => Ok(SlackFlavor::Slack),
+            "mattermost" => Ok(SlackFlavor::Mattermost),
+            _ => Err(String::from("expected slack or mattermost")),
+        }
+    }
+}
+
+/// Slack and Mattermost channel
+#[derive(Clone)]
+pub struct Slack {
+    pub flavor: SlackFlavor,
+    pub webhook: ChatWebhook,
+
+    /// Channel of the messages, instead of the one of the webhook
+    pub channel: Option<String>,
+    pub username: Option<String>,
+}
+
+/// Escape the characters Slack interprets in `mrkdwn` text, so reports
+/// cannot add links or mentions like `<!channel>`
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Slack {
+    /// The Block Kit payload of a message
+    fn blocks(message: &ChatMessage) -> Value {
+        let title = match &message.link {
+            Some(link) => format!("*<{}|{}>*", escape(link), escape(&message.title)),
+            None => format!("*{}*", escape(&message.title)),
+        };
+        let fields: Vec<Value> = message
+            .fields
+            .iter()
+            .map(|field| {
+                json!({
+                    "type": "mrkdwn",
+                    "text": format!("*{}*\n{}", field.name, escape(&field.value)),
+                })
+            })
+            .collect();
+
+        let mut blocks = vec![
+            json!({ "type": "section", "text": { "type": "mrkdwn", "text": title } }),
+            json!({ "type": "section", "fields": fields }),
+        ];
+        if let Some(note) = message.suppressed_note() {
+            blocks.push(json!({
+                "type": "context",
+                "elements": [{ "type": "mrkdwn", "text": note }],
+            }));
+        }
+
+        // Blocks are wrapped in an attachment, which has the colour
+        json!({
+            "text": escape(&message.title),
+            "attachments": [{
+                "color": format!("#{:06x}", message.severity.color()),
+                "blocks": blocks,
+            }],
+        })
+    }
+
+    /// The attachment payload of a message, for Mattermost
+    fn attachment(message: &ChatMessage) -> Value {
+        let fields: Vec<Value> = message
+            .fields
+            .iter()
+            .map(|field| {
+                json!({
+                    "title": field.name,
+                    "value": escape(&field.value),
+                    "short": field.value.len() <= 40,
+                })
+            })
+            .collect();
+
+        let mut attachment = json!({
+            "fallback": escape(&message.title),
+            "color": format!("#{:06x}", message.severity.color()),
+            "title": escape(&message.title),
+            "fields": fields,
+        });
+        if let Some(link) = &message.link {
+            attachment["title_link"] = json!(link);
+        }
+        if let Some(note) = message.suppressed_note() {
+            attachment["footer"] = json!(note);
+        }
+
+        json!({ "attachments": [attachment] })
+    }
+
+    /// The payload posted to the webhook
+    fn payload(&self, message: &ChatMessage) -> Value {
+        let mut payload = match self.flavor {
+            SlackFlavor::Slack => Self::blocks(message),
+            SlackFlavor::Mattermost => Self::attachment(message),
+        };
+        if let Some(channel) = &self.channel {
+            payload["channel"] = json!(channel);
+        }
+        if let Some(username) = &self.username {
+            payload["username"] = json!(username);
+        }
+
+        payload
+    }
+}
+
+#[async_trait]
+impl Channel for Slack {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let settings = ChatSettings::from_env(&mut env, "MORBO_SLACK");
+        let flavor = env
+            .parse_optional("MORBO_SLACK_FLAVOR")
+            .unwrap_or(SlackFlavor::Slack);
+        let channel = env.optional("MORBO_SLACK_CHANNEL");
+        let username = env.optional("MORBO_SLACK_USERNAME");
+
+        env.finish()?;
+
+        Ok(Slack {
+            flavor,
+            webhook: settings.webhook()?,
+            channel,
+            username,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.flavor {
+            SlackFlavor::Slack => "slack",
+            SlackFlavor::Mattermost => "mattermost",
+        }
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        self.webhook.check().await
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        self.webhook
+            .post(report, |message| self.payload(message))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chat::{RateLimit, Severity};
+    use crate::csp::csp_report_content::CspReportContent;
+    use reqwest::Client;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    fn report(disposition: &str) -> ReceivedReport {
+        let mut csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://shop.example.org/cart?a=1&b=2",
+            "default-src 'self'",
+            "",
+            "script-src",
+        );
+        csp_report.disposition = Some(String::from(disposition));
+        ReceivedReport::new(csp_report)
+    }
+
+    fn slack(flavor: SlackFlavor, webhook_url: Url, rate_limit: u32) -> Slack {
+        Slack {
+            flavor,
+            webhook: ChatWebhook {
+                url: webhook_url,
+                rate_limit: Arc::new(RateLimit::new(rate_limit, Duration::from_secs(60))),
+                client: Client::new(),
+            },
+            channel: Some(String::from("#security")),
+            username: None,
+        }
+    }
+
+    #[test]
+    fn it_builds_block_kit_messages() {
+        let slack = slack(
+            SlackFlavor::Slack,
+            Url::parse("https://hooks.slack.com/services/T/B/X").unwrap(),
+            0,
+        );
+        let payload = slack.payload(&ChatMessage::new(&report("enforce"), 3));
+
+        assert_eq!("#security", payload["channel"]);
+        assert!(payload.get("username").is_none());
+
+        let attachment = &payload["attachments"][0];
+        assert_eq!(
+            format!("#{:06x}", Severity::Enforce.color()),
+            attachment["color"]
+        );
+        assert_eq!(
+            "*<https://shop.example.org/cart?a=1&amp;b=2|CSP violation: script-src blocked evil.example.com on shop.example.org>*",
+            attachment["blocks"][0]["text"]["text"]
+        );
+        assert_eq!(
+            "*Blocked URI*\nhttps://evil.example.com/x.js",
+            attachment["blocks"][1]["fields"][1]["text"]
+        );
+        assert_eq!(
+            "3 more reports were not posted because of the rate limit",
+            attachment["blocks"][2]["elements"][0]["text"]
+        );
+    }
+
+    #[test]
+    fn it_builds_mattermost_messages() {
+        let slack = slack(
+            SlackFlavor::Mattermost,
+            Url::parse("https://chat.example.org/hooks/x").unwrap(),
+            0,
+        );
+        let payload = slack.payload(&ChatMessage::new(&report("report"), 0));
+
+        let attachment = &payload["attachments"][0];
+        assert_eq!(
+            format!("#{:06x}", Severity::Report.color()),
+            attachment["color"]
+        );
+        assert_eq!(
+            "https://shop.example.org/cart?a=1&b=2",
+            attachment["title_link"]
+        );
+        assert_eq!("Directive", attachment["fields"][0]["title"]);
+        assert_eq!("script-src", attachment["fields"][0]["value"]);
+        assert_eq!(true, attachment["fields"][0]["short"]);
+        assert!(attachment.get("footer").is_none());
+    }
+
+    #[test]
+    fn it_escapes_mentions() {
+        let report = ReceivedReport::new(CspReportContent::default(
+            "<!channel>",
+            "https://shop.example.org/",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+        let message = ChatMessage::new(&report, 0);
+        let url = Url::parse("https://hooks.slack.com/services/T/B/X").unwrap();
+
+        for flavor in [SlackFlavor::Slack, SlackFlavor::Mattermost] {
+            let payload = slack(flavor, url.clone(), 0).payload(&message).to_string();
+
+            assert!(!payload.contains("<!channel>"));
+            assert!(payload.contains("&lt;!channel&gt;"));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_stops_posting_at_the_rate_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while !request.ends_with(b"}") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let slack = slack(SlackFlavor::Slack, url, 1);
+        slack.send_report(&report("enforce")).await.unwrap();
+        // Over the limit, the report is dropped without any request
+        slack.send_report(&report("enforce")).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert!(request.contains("\"channel\":\"#security\""));
+        assert_eq!(None, slack.webhook.rate_limit.acquire());
+    }
+}
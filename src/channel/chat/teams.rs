@@ -0,0 +1,142 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::channel::chat::{ChatMessage, ChatSettings, ChatWebhook, Severity};
+use crate::channel::support::EnvReader;
+use crate::channel::Channel;
+use crate::csp::received_report::ReceivedReport;
+
+/// Microsoft Teams channel
+#[derive(Clone)]
+pub struct Teams {
+    pub webhook: ChatWebhook,
+}
+
+impl Teams {
+    /// The adaptive card payload of a message
+    fn payload(message: &ChatMessage) -> Value {
+        // Adaptive cards only have a few named colours
+        let color = match message.severity {
+            Severity::Enforce => "Attention",
+            Severity::Report => "Warning",
+            Severity::Unknown => "Default",
+        };
+        let facts: Vec<Value> = message
+            .fields
+            .iter()
+            .map(|field| json!({ "title": field.name, "value": field.value }))
+            .collect();
+
+        let mut body = vec![
+            json!({
+                "type": "TextBlock",
+                "text": message.title,
+                "weight": "Bolder",
+                "size": "Medium",
+                "color": color,
+                "wrap": true,
+            }),
+            json!({ "type": "FactSet", "facts": facts }),
+        ];
+        if let Some(note) = message.suppressed_note() {
+            body.push(json!({
+                "type": "TextBlock",
+                "text": note,
+                "size": "Small",
+                "isSubtle": true,
+                "wrap": true,
+            }));
+        }
+
+        let mut card = json!({
+            "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+            "type": "AdaptiveCard",
+            "version": "1.4",
+            "msteams": { "width": "Full" },
+            "body": body,
+        });
+        if let Some(link) = &message.link {
+            card["actions"] = json!([{
+                "type": "Action.OpenUrl",
+                "title": "Open the document",
+                "url": link,
+            }]);
+        }
+
+        json!({
+            "type": "message",
+            "attachments": [{
+                "contentType": "application/vnd.microsoft.card.adaptive",
+                "contentUrl": null,
+                "content": card,
+            }],
+        })
+    }
+}
+
+#[async_trait]
+impl Channel for Teams {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let settings = ChatSettings::from_env(&mut env, "MORBO_TEAMS");
+
+        env.finish()?;
+
+        Ok(Teams {
+            webhook: settings.webhook()?,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "teams"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        self.webhook.check().await
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        self.webhook.post(report, Self::payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    #[test]
+    fn it_builds_adaptive_cards() {
+        let mut csp_report = CspReportContent::default(
+            "inline",
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src",
+        );
+        csp_report.disposition = Some(String::from("enforce"));
+        let payload = Teams::payload(&ChatMessage::new(&ReceivedReport::new(csp_report), 1));
+
+        let attachment = &payload["attachments"][0];
+        assert_eq!(
+            "application/vnd.microsoft.card.adaptive",
+            attachment["contentType"]
+        );
+
+        let card = &attachment["content"];
+        assert_eq!(
+            "CSP violation: script-src blocked inline on shop.example.org",
+            card["body"][0]["text"]
+        );
+        assert_eq!("Attention", card["body"][0]["color"]);
+        assert_eq!("Blocked URI", card["body"][1]["facts"][1]["title"]);
+        assert_eq!("inline", card["body"][1]["facts"][1]["value"]);
+        assert_eq!(
+            "1 more report was not posted because of the rate limit",
+            card["body"][2]["text"]
+        );
+        assert_eq!("https://shop.example.org/cart", card["actions"][0]["url"]);
+    }
+}
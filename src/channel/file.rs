@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Context, Error};
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::{debug, error};
+
+use crate::channel::support::EnvReader;
+use crate::channel::Channel;
+use crate::csp::received_report::ReceivedReport;
+
+/// A size in bytes, like `1048576`, `512K`, `100M` or `1G`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || String::from("expected a number of bytes, optionally followed by K, M or G");
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+        let multiplier = match unit.trim().to_uppercase().trim_end_matches('B') {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => return Err(error()),
+        };
+        let number: u64 = number.parse().map_err(|_| error())?;
+        if number == 0 {
+            return Err(String::from("the size cannot be empty"));
+        }
+
+        Ok(ByteSize(number * multiplier))
+    }
+}
+
+/// The file being written
+#[derive(Debug)]
+struct Segment {
+    file: File,
+    size: u64,
+
+    /// When the last report was written, which names the segment once
+    /// rotated
+    last_write: DateTime<Utc>,
+}
+
+/// The state of a file, shared by every channel writing to it
+#[derive(Debug, Default)]
+struct FileState {
+    /// The open file, shared by the reports written concurrently
+    segment: Mutex<Option<Segment>>,
+
+    /// Held while compressing and removing the rotated files
+    archive: Mutex<()>,
+}
+
+/// The files by path. The global and project channels writing to the same
+/// file share its state, so one of them rotating or removing a file is seen
+/// by the others.
+static FILES: OnceLock<Mutex<HashMap<PathBuf, Arc<FileState>>>> = OnceLock::new();
+
+/// JSON Lines file channel
+#[derive(Clone)]
+pub struct JsonLines {
+    pub path: PathBuf,
+
+    /// Rotate the file before it grows over this size
+    pub max_size: Option<u64>,
+
+    /// Rotate the file when the day changes, in UTC
+    pub daily: bool,
+
+    /// Compress the rotated files with gzip
+    pub compress: bool,
+
+    /// Number of rotated files kept, 0 meaning all of them
+    pub keep: usize,
+
+    state: Arc<FileState>,
+}
+
+impl JsonLines {
+    pub fn new(path: PathBuf) -> Self {
+        let key = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+        let state = FILES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone();
+
+        JsonLines {
+            path,
+            max_size: None,
+            daily: false,
+            compress: true,
+            keep: 0,
+            state,
+        }
+    }
+
+    /// The file name of the channel, split as stem and extension with its
+    /// dot
+    fn file_name(&self) -> (String, String) {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = self
+            .path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+
+        (stem, extension)
+    }
+
+    fn open(&self, now: DateTime<Utc>) -> Result<Segment, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Unable to open {}", self.path.display()))?;
+        let metadata = file.metadata()?;
+
+        // An existing file was last written when it was modified
+        let last_write = match metadata.modified() {
+            Ok(modified) if metadata.len() > 0 => DateTime::from(modified),
+            _ => now,
+        };
+
+        Ok(Segment {
+            file,
+            size: metadata.len(),
+            last_write,
+        })
+    }
+
+    fn needs_rotation(&self, segment: &Segment, length: u64, now: DateTime<Utc>) -> bool {
+        if segment.size == 0 {
+            return false;
+        }
+
+        self.max_size
+            .is_some_and(|max_size| segment.size + length > max_size)
+            || (self.daily && segment.last_write.num_days_from_ce() != now.num_days_from_ce())
+    }
+
+    /// Rename the file, named after the time of its last report
+    fn rotate(&self, segment: Segment) -> Result<PathBuf, Error> {
+        drop(segment.file);
+
+        let (stem, extension) = self.file_name();
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let name = format!("{}-{}", stem, segment.last_write.format("%Y%m%d-%H%M%S"));
+
+        // Files rotated during the same second are numbered, so they are
+        // sorted after the first one
+        let mut rotated = dir.join(format!("{}{}", name, extension));
+        let mut index = 1;
+        while rotated.exists() || Self::compressed_path(&rotated).exists() {
+            rotated = dir.join(format!("{}_{:03}{}", name, index, extension));
+            index += 1;
+        }
+
+        fs::rename(&self.path, &rotated)
+            .with_context(|| format!("Unable to rotate {}", self.path.display()))?;
+        debug!("{} rotated to {}", self.path.display(), rotated.display());
+
+        Ok(rotated)
+    }
+
+    fn compressed_path(path: &Path) -> PathBuf {
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".gz");
+        PathBuf::from(compressed)
+    }
+
+    /// Compress a rotated file, and remove it
+    fn compress(path: &Path) -> Result<(), Error> {
+        let compressed = Self::compressed_path(path);
+
+        let written = File::open(path)
+            .and_then(|mut input| {
+                let mut encoder =
+                    GzEncoder::new(File::create(&compressed)?, Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()
+            })
+            .with_context(|| format!("Unable to compress {}", path.display()));
+
+        match written {
+            Ok(()) => fs::remove_file(path)?,
+            // The rotated file is kept as is
+            Err(e) => {
+                let _ = fs::remove_file(&compressed);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The rotated files, the oldest first
+    fn rotated_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let (stem, extension) = self.file_name();
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                is_rotated(&name, &stem, &extension)
+            })
+            .collect();
+        files.sort();
+
+        Ok(files)
+    }
+
+    /// Compress a rotated file, and remove the oldest ones over the
+    /// retention count. Errors are only logged, as the report was written.
+    fn archive(&self, rotated: &Path) {
+        let _archive = self.state.archive.lock().unwrap();
+
+        if self.compress {
+            if let Err(e) = Self::compress(rotated) {
+                error!("{:#}", e);
+            }
+        }
+
+        if self.keep == 0 {
+            return;
+        }
+
+        let removed = self.rotated_files().and_then(|files| {
+            let count = files.len().saturating_sub(self.keep);
+            for file in &files[..count] {
+                fs::remove_file(file)
+                    .with_context(|| format!("Unable to remove {}", file.display()))?;
+                debug!("{} removed", file.display());
+            }
+            Ok(())
+        });
+        if let Err(e) = removed {
+            error!("{:#}", e);
+        }
+    }
+
+    /// Append a line to the file, rotating it first if needed
+    fn write(&self, line: &[u8], now: DateTime<Utc>) -> Result<(), Error> {
+        let length = line.len() as u64;
+
+        let rotated = {
+            let mut current = self.state.segment.lock().unwrap();
+            // The file is opened again after an error
+            let mut segment = match current.take() {
+                Some(segment) => segment,
+                None => self.open(now)?,
+            };
+
+            let mut rotated = None;
+            if self.needs_rotation(&segment, length, now) {
+                rotated = Some(self.rotate(segment)?);
+                segment = self.open(now)?;
+            }
+
+            // A single write per line, so lines are never interleaved
+            segment
+                .file
+                .write_all(line)
+                .with_context(|| format!("Unable to write to {}", self.path.display()))?;
+            segment.size += length;
+            segment.last_write = now;
+            *current = Some(segment);
+
+            rotated
+        };
+
+        if let Some(rotated) = rotated {
+            self.archive(&rotated);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Channel for JsonLines {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let path = env.required("MORBO_FILE_PATH");
+        let max_size = env
+            .parse_optional::<ByteSize>("MORBO_FILE_MAX_SIZE")
+            .map(|size| size.0);
+        let daily = env
+            .parse_optional("MORBO_FILE_ROTATE_DAILY")
+            .unwrap_or(false);
+        let compress = env.parse_optional("MORBO_FILE_COMPRESS").unwrap_or(true);
+        let keep = env.parse_optional("MORBO_FILE_KEEP").unwrap_or(0);
+
+        env.finish()?;
+
+        Ok(JsonLines {
+            max_size,
+            daily,
+            compress,
+            keep,
+            ..JsonLines::new(PathBuf::from(path))
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        self.open(Utc::now())?;
+
+        Ok(())
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(report)?;
+        line.push(b'\n');
+
+        let channel = self.clone();
+        tokio::task::spawn_blocking(move || channel.write(&line, Utc::now())).await?
+    }
+}
+
+/// Whether a file name is the one of a rotated file:
+/// `{stem}-%Y%m%d-%H%M%S{extension}`, with a `_NNN` suffix on collisions,
+/// and `.gz` once compressed
+fn is_rotated(name: &str, stem: &str, extension: &str) -> bool {
+    let suffix = match name
+        .strip_prefix(stem)
+        .and_then(|suffix| suffix.strip_prefix('-'))
+    {
+        Some(suffix) => suffix,
+        None => return false,
+    };
+    let suffix = suffix.strip_suffix(".gz").unwrap_or(suffix);
+    let suffix = match suffix.strip_suffix(extension) {
+        Some(suffix) => suffix,
+        None => return false,
+    };
+    let (timestamp, index) = match suffix.split_once('_') {
+        Some((timestamp, index)) => (timestamp, Some(index)),
+        None => (suffix, None),
+    };
+
+    let digits = |text: &str| text.bytes().all(|byte| byte.is_ascii_digit());
+    let timestamp_matches = match timestamp.split_once('-') {
+        Some((date, time)) => date.len() == 8 && time.len() == 6 && digits(date) && digits(time),
+        None => false,
+    };
+
+    timestamp_matches && index.is_none_or(|index| index.len() >= 3 && digits(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn line(blocked_uri: &str) -> Vec<u8> {
+        let report = ReceivedReport::new(CspReportContent::default(
+            blocked_uri,
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+        let mut line = serde_json::to_vec(&report).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    fn time(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn names(channel: &JsonLines) -> Vec<String> {
+        channel
+            .rotated_files()
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn it_parses_sizes() {
+        assert_eq!(Ok(ByteSize(1000)), "1000".parse());
+        assert_eq!(Ok(ByteSize(512 * 1024)), "512K".parse());
+        assert_eq!(Ok(ByteSize(100 * 1024 * 1024)), "100mb".parse());
+        assert!("0M".parse::<ByteSize>().is_err());
+        assert!("1T".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn it_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let channel = JsonLines {
+            max_size: Some(line("inline").len() as u64 + 1),
+            compress: false,
+            ..JsonLines::new(dir.path().join("reports.jsonl"))
+        };
+        let now = time("2024-05-01T12:30:00Z");
+
+        for blocked_uri in ["inline", "eval", "data"] {
+            channel.write(&line(blocked_uri), now).unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                "reports-20240501-123000.jsonl",
+                "reports-20240501-123000_001.jsonl"
+            ],
+            names(&channel)
+        );
+        let current = fs::read_to_string(&channel.path).unwrap();
+        let report: ReceivedReport = serde_json::from_str(&current).unwrap();
+        assert_eq!("data", report.csp_report.blocked_uri);
+    }
+
+    #[test]
+    fn it_rotates_daily_and_compresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let channel = JsonLines {
+            daily: true,
+            keep: 1,
+            ..JsonLines::new(dir.path().join("reports.jsonl"))
+        };
+
+        channel
+            .write(&line("inline"), time("2024-05-01T23:59:00Z"))
+            .unwrap();
+        channel
+            .write(&line("eval"), time("2024-05-01T23:59:30Z"))
+            .unwrap();
+        channel
+            .write(&line("data"), time("2024-05-02T00:00:10Z"))
+            .unwrap();
+        assert_eq!(vec!["reports-20240501-235930.jsonl.gz"], names(&channel));
+
+        let mut content = String::new();
+        GzDecoder::new(File::open(dir.path().join("reports-20240501-235930.jsonl.gz")).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(2, content.lines().count());
+
+        // Only the last rotated file is kept
+        channel
+            .write(&line("inline"), time("2024-05-03T08:00:00Z"))
+            .unwrap();
+        assert_eq!(vec!["reports-20240502-000010.jsonl.gz"], names(&channel));
+    }
+
+    #[test]
+    fn it_only_prunes_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let channel = JsonLines {
+            daily: true,
+            compress: false,
+            keep: 1,
+            ..JsonLines::new(dir.path().join("reports.jsonl"))
+        };
+        let unrelated = [
+            "reports-foo.jsonl",
+            "reports-2024.jsonl.gz",
+            "reports-20240501-235930_x.jsonl",
+            "reports-20240501-235930.jsonl.bak",
+        ];
+        for name in unrelated {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        for day in ["2024-05-01", "2024-05-02", "2024-05-03"] {
+            channel
+                .write(&line("inline"), time(&format!("{}T08:00:00Z", day)))
+                .unwrap();
+        }
+
+        assert_eq!(vec!["reports-20240502-080000.jsonl"], names(&channel));
+        for name in unrelated {
+            assert!(dir.path().join(name).exists(), "{} was removed", name);
+        }
+    }
+
+    #[test]
+    fn it_writes_whole_lines_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let channel = JsonLines {
+            max_size: Some(4096),
+            compress: false,
+            ..JsonLines::new(dir.path().join("reports.jsonl"))
+        };
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let channel = channel.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        channel.write(&line("inline"), Utc::now()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut files = channel.rotated_files().unwrap();
+        files.push(channel.path.clone());
+        let mut count = 0;
+        for file in files {
+            for line in fs::read_to_string(file).unwrap().lines() {
+                serde_json::from_str::<ReceivedReport>(line).unwrap();
+                count += 1;
+            }
+        }
+        assert_eq!(400, count);
+    }
+
+    #[test]
+    fn it_shares_the_file_between_channels() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reports.jsonl");
+        let max_size = Some(line("inline").len() as u64 * 3);
+
+        // Like the global and project channels, loaded independently
+        let global = JsonLines {
+            max_size,
+            ..JsonLines::new(path.clone())
+        };
+        let project = JsonLines {
+            max_size,
+            ..JsonLines::new(path.clone())
+        };
+
+        let threads: Vec<_> = [global.clone(), project]
+            .into_iter()
+            .map(|channel| {
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        channel.write(&line("inline"), Utc::now()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // No line is written to a file already rotated and compressed
+        let mut count = fs::read_to_string(&path).unwrap().lines().count();
+        for file in global.rotated_files().unwrap() {
+            let mut content = String::new();
+            GzDecoder::new(File::open(file).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            count += content.lines().count();
+        }
+        assert_eq!(100, count);
+    }
+}
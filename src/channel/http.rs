@@ -0,0 +1,48 @@
+use anyhow::{anyhow, Error};
+use reqwest::StatusCode;
+
+use crate::channel::PermanentError;
+
+/// Turn the status of a response into an error, permanent when retrying
+/// the same request cannot succeed
+pub fn check_status(url: &str, status: StatusCode) -> Result<(), Error> {
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let error = anyhow!("{} answered with status {}", url, status);
+    if status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+    {
+        Err(error)
+    } else {
+        Err(PermanentError(error).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_retries_temporary_statuses() {
+        let url = "https://hooks.example.com/csp";
+
+        assert!(check_status(url, StatusCode::NO_CONTENT).is_ok());
+        for status in [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            assert!(!check_status(url, status)
+                .unwrap_err()
+                .is::<PermanentError>());
+        }
+        for status in [StatusCode::BAD_REQUEST, StatusCode::UNAUTHORIZED] {
+            assert!(check_status(url, status)
+                .unwrap_err()
+                .is::<PermanentError>());
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::csp::condition::host;
+use crate::csp::received_report::ReceivedReport;
+
+/// What makes two reports the same violation in a digest
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct DigestKey {
+    pub directive: String,
+    pub blocked_uri: String,
+    pub document_host: Option<String>,
+}
+
+impl DigestKey {
+    pub fn new(report: &ReceivedReport) -> Self {
+        let csp_report = &report.csp_report;
+
+        DigestKey {
+            directive: csp_report
+                .effective_directive
+                .clone()
+                .unwrap_or_else(|| csp_report.violated_directive.clone()),
+            blocked_uri: csp_report.blocked_uri.clone(),
+            document_host: host(&csp_report.document_uri),
+        }
+    }
+}
+
+/// Maximum number of violations remembered to tell the new ones, the
+/// oldest being forgotten first
+const MAX_SEEN: usize = 10_000;
+
+/// What makes a violation new: the blocked host instead of the full
+/// blocked URI, so a URI varying on each request is only notified once
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeenKey {
+    directive: String,
+    blocked: String,
+    document_host: Option<String>,
+}
+
+impl SeenKey {
+    fn new(key: DigestKey) -> Self {
+        SeenKey {
+            blocked: host(&key.blocked_uri).unwrap_or(key.blocked_uri),
+            directive: key.directive,
+            document_host: key.document_host,
+        }
+    }
+}
+
+/// The violations already reported, bounded to `MAX_SEEN`
+#[derive(Debug, Default)]
+struct Seen {
+    keys: HashSet<SeenKey>,
+    order: VecDeque<SeenKey>,
+}
+
+impl Seen {
+    fn contains(&self, key: &SeenKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    fn insert(&mut self, key: SeenKey) {
+        if !self.keys.insert(key.clone()) {
+            return;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > MAX_SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A violation of a digest, and how often it was reported
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestEntry {
+    #[serde(flatten)]
+    pub key: DigestKey,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+
+    /// The last report of the violation
+    pub report: ReceivedReport,
+}
+
+impl DigestEntry {
+    /// Add the reports of the same violation
+    fn merge(&mut self, other: DigestEntry) {
+        self.count += other.count;
+        self.first_seen = self.first_seen.min(other.first_seen);
+        if other.last_seen > self.last_seen {
+            self.last_seen = other.last_seen;
+            self.report = other.report;
+        }
+    }
+}
+
+/// The violations reported during a window, the most frequent first
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestBatch {
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    pub entries: Vec<DigestEntry>,
+}
+
+impl DigestBatch {
+    /// The number of reports in the batch
+    pub fn count(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.count).sum()
+    }
+}
+
+/// The length of a digest window, like `30m`, `1h` or `1d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigestWindow(pub Duration);
+
+impl FromStr for DigestWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error =
+            || String::from("expected a number of seconds, or a number followed by s, m, h or d");
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+
+        let multiplier = match unit.trim() {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(error()),
+        };
+        let number: u64 = number.parse().map_err(|_| error())?;
+        if number == 0 {
+            return Err(String::from("the window cannot be empty"));
+        }
+
+        Ok(DigestWindow(Duration::from_secs(number * multiplier)))
+    }
+}
+
+#[derive(Debug)]
+struct DigestState {
+    since: DateTime<Utc>,
+    entries: HashMap<DigestKey, DigestEntry>,
+
+    /// The violations reported since morbo started
+    seen: Seen,
+}
+
+/// Reports buffered until the end of the window, to be sent in a single
+/// email
+#[derive(Debug)]
+pub struct Digest {
+    pub window: Duration,
+
+    /// Send an email right away for violations never reported before
+    pub notify_new: bool,
+
+    started: AtomicBool,
+    state: Mutex<DigestState>,
+}
+
+impl Digest {
+    pub fn new(window: Duration, notify_new: bool) -> Self {
+        Digest {
+            window,
+            notify_new,
+            started: AtomicBool::new(false),
+            state: Mutex::new(DigestState {
+                since: Utc::now(),
+                entries: HashMap::new(),
+                seen: Seen::default(),
+            }),
+        }
+    }
+
+    /// Mark the digest as started, and return whether it was not already
+    pub fn start(&self) -> bool {
+        !self.started.swap(true, Ordering::SeqCst)
+    }
+
+    /// Test if the violation of a report was never reported before
+    pub fn is_new(&self, report: &ReceivedReport) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.seen.contains(&SeenKey::new(DigestKey::new(report)))
+    }
+
+    /// Add a report to the current window
+    pub fn add(&self, report: &ReceivedReport) {
+        let key = DigestKey::new(report);
+        let entry = DigestEntry {
+            key: key.clone(),
+            count: 1 + report.duplicates,
+            first_seen: report.received_at,
+            last_seen: report.received_at,
+            report: report.clone(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.seen.insert(SeenKey::new(key.clone()));
+        match state.entries.get_mut(&key) {
+            Some(existing) => existing.merge(entry),
+            None => {
+                state.entries.insert(key, entry);
+            }
+        }
+    }
+
+    /// Take the violations of the current window, if any, and start a new
+    /// window
+    pub fn take(&self) -> Option<DigestBatch> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let since = std::mem::replace(&mut state.since, now);
+
+        if state.entries.is_empty() {
+            return None;
+        }
+
+        let mut entries: Vec<DigestEntry> = state.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.first_seen.cmp(&b.first_seen))
+        });
+
+        Some(DigestBatch {
+            since,
+            until: now,
+            entries,
+        })
+    }
+
+    /// Put back a batch which could not be sent, to send it with the next
+    /// window
+    pub fn restore(&self, batch: DigestBatch) {
+        let mut state = self.state.lock().unwrap();
+        state.since = state.since.min(batch.since);
+
+        for entry in batch.entries {
+            match state.entries.get_mut(&entry.key) {
+                Some(existing) => existing.merge(entry),
+                None => {
+                    state.entries.insert(entry.key.clone(), entry);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    fn report(blocked_uri: &str) -> ReceivedReport {
+        ReceivedReport::new(CspReportContent::default(
+            blocked_uri,
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ))
+    }
+
+    #[test]
+    fn it_parses_windows() {
+        assert_eq!(
+            Ok(DigestWindow(Duration::from_secs(90))),
+            "90".parse::<DigestWindow>()
+        );
+        assert_eq!(
+            Ok(DigestWindow(Duration::from_secs(30 * 60))),
+            "30m".parse::<DigestWindow>()
+        );
+        assert_eq!(
+            Ok(DigestWindow(Duration::from_secs(24 * 60 * 60))),
+            "1d".parse::<DigestWindow>()
+        );
+        assert!("0h".parse::<DigestWindow>().is_err());
+        assert!("1w".parse::<DigestWindow>().is_err());
+        assert!("h".parse::<DigestWindow>().is_err());
+    }
+
+    #[test]
+    fn it_groups_reports_by_violation() {
+        let digest = Digest::new(Duration::from_secs(60), true);
+        let first = report("https://evil.example.com/x.js");
+        let other = report("inline");
+
+        assert!(digest.is_new(&first));
+        digest.add(&first);
+        digest.add(&other);
+        let last = report("https://evil.example.com/x.js");
+        digest.add(&last);
+        assert!(!digest.is_new(&first));
+
+        let batch = digest.take().unwrap();
+        assert_eq!(3, batch.count());
+        assert_eq!(2, batch.entries.len());
+
+        let entry = &batch.entries[0];
+        assert_eq!("https://evil.example.com/x.js", entry.key.blocked_uri);
+        assert_eq!("script-src", entry.key.directive);
+        assert_eq!(Some("shop.example.org"), entry.key.document_host.as_deref());
+        assert_eq!(2, entry.count);
+        assert_eq!(first.received_at, entry.first_seen);
+        assert_eq!(last.received_at, entry.last_seen);
+
+        // The window is empty, but the violations are still known
+        assert!(digest.take().is_none());
+        assert!(!digest.is_new(&other));
+    }
+
+    #[test]
+    fn it_restores_unsent_batches() {
+        let digest = Digest::new(Duration::from_secs(60), false);
+        digest.add(&report("inline"));
+        let batch = digest.take().unwrap();
+
+        digest.add(&report("inline"));
+        digest.restore(batch.clone());
+
+        let restored = digest.take().unwrap();
+        assert_eq!(2, restored.count());
+        assert_eq!(batch.since, restored.since);
+    }
+
+    #[test]
+    fn it_bounds_the_known_violations() {
+        let digest = Digest::new(Duration::from_secs(60), true);
+        digest.add(&report("https://evil.example.com/x.js?v=1"));
+        assert!(!digest.is_new(&report("https://evil.example.com/y.js?v=2")));
+
+        for i in 0..MAX_SEEN {
+            digest.add(&report(&format!("https://cdn{}.example.com/", i)));
+        }
+        assert_eq!(MAX_SEEN, digest.state.lock().unwrap().seen.order.len());
+        assert!(digest.is_new(&report("https://evil.example.com/x.js")));
+    }
+}
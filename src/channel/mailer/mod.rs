@@ -0,0 +1,364 @@
+pub mod digest;
+pub mod routing;
+pub mod smtp;
+pub mod template;
+
+use crate::csp::received_report::ReceivedReport;
+use anyhow::{anyhow, Context, Error};
+use async_trait::async_trait;
+use digest::{Digest, DigestBatch, DigestWindow};
+use lettre::smtp::error::Error as SmtpError;
+use lettre_email::mime::APPLICATION_JSON;
+use lettre_email::EmailBuilder;
+use routing::{Recipient, Recipients, Routes};
+use smtp::Smtp;
+use std::path::Path;
+use std::sync::Arc;
+use template::{RenderedEmail, Templates};
+
+use crate::channel::support::{check_connection, EnvReader};
+use crate::channel::{Channel, PermanentError};
+use lettre::Transport;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info};
+
+/// Mailer channel
+#[derive(Clone)]
+pub struct Mailer {
+    pub from_name: String,
+    pub from_email: String,
+
+    /// Recipients of the reports matching no route
+    pub recipients: Recipients,
+    pub routes: Arc<Routes>,
+
+    pub templates: Arc<Templates>,
+
+    /// Reports waiting for the next digest, when reports are not sent one
+    /// by one
+    pub digest: Option<Arc<Digest>>,
+
+    pub smtp: Smtp,
+}
+
+impl Mailer {
+    /// Build and send an email, blocking until it is sent
+    fn send_email(
+        &self,
+        recipients: &Recipients,
+        rendered: RenderedEmail,
+        attachment: (&[u8], &str),
+    ) -> Result<(), Error> {
+        let mut email = EmailBuilder::new().from((&self.from_email, &self.from_name));
+        for recipient in &recipients.to {
+            email = email.to(recipient);
+        }
+        for recipient in &recipients.cc {
+            email = email.cc(recipient);
+        }
+        for recipient in &recipients.bcc {
+            email = email.bcc(recipient);
+        }
+
+        let (content, filename) = attachment;
+        let email = email
+            .subject(rendered.subject)
+            .alternative(rendered.html, rendered.text)
+            .attachment(content, filename, &APPLICATION_JSON)
+            .and_then(EmailBuilder::build)
+            .map_err(|e| PermanentError(Error::new(e).context("Unable to build the email")))?;
+
+        let mut transport = self
+            .smtp
+            .transport()
+            .with_context(|| format!("Unable to connect to {}", self.smtp.hostname))?;
+
+        let response = match transport.send(email.into()) {
+            Ok(response) => response,
+            Err(e @ SmtpError::Permanent(_)) => {
+                return Err(PermanentError(Error::new(e).context("Email rejected")).into())
+            }
+            Err(e) => return Err(Error::new(e).context("Unable to send the email")),
+        };
+        debug!("Email sent: {}", response.message.join(" "));
+
+        Ok(())
+    }
+
+    /// Build and send the email of a report, blocking until it is sent
+    fn send_report_blocking(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let recipients = self.routes.recipients(report, &self.recipients);
+        if recipients.is_empty() {
+            debug!("No recipient for the report, email not sent");
+            return Ok(());
+        }
+
+        let rendered = self
+            .templates
+            .render(report)
+            .map_err(|e| PermanentError(e.context("Unable to render the email")))?;
+        let json = serde_json::to_vec_pretty(&report.csp_report)?;
+
+        self.send_email(&recipients, rendered, (&json, "report.json"))
+    }
+
+    /// Send the email of a report on a blocking thread. lettre's timeout
+    /// only applies once connected, so the whole send is bounded.
+    async fn send_report_in_background(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let mailer = self.clone();
+        let report = report.clone();
+        // lettre's SMTP transport is blocking
+        let send = tokio::task::spawn_blocking(move || mailer.send_report_blocking(&report));
+
+        tokio::time::timeout(self.smtp.timeout, send)
+            .await
+            .map_err(|_| anyhow!("Timeout sending the email to {}", self.smtp.hostname))??
+    }
+
+    /// Send the digest of a window, one email per set of recipients. The
+    /// violations which could not be sent are put back in the digest.
+    fn send_digest_blocking(&self, digest: &Digest, batch: DigestBatch) {
+        let mut batches: Vec<(Recipients, DigestBatch)> = vec![];
+        for entry in batch.entries {
+            let recipients = self.routes.recipients(&entry.report, &self.recipients);
+            match batches.iter_mut().find(|(other, _)| *other == recipients) {
+                Some((_, batch)) => batch.entries.push(entry),
+                None => batches.push((
+                    recipients,
+                    DigestBatch {
+                        entries: vec![entry],
+                        ..batch
+                    },
+                )),
+            }
+        }
+
+        for (recipients, batch) in batches {
+            if recipients.is_empty() {
+                debug!(
+                    "No recipient for {} violations, digest not sent",
+                    batch.entries.len()
+                );
+                continue;
+            }
+
+            let sent = self
+                .templates
+                .render_digest(&batch)
+                .context("Unable to render the digest")
+                .and_then(|rendered| {
+                    let json = serde_json::to_vec_pretty(&batch.entries)?;
+                    self.send_email(&recipients, rendered, (&json, "digest.json"))
+                });
+
+            match sent {
+                Ok(()) => info!("Digest of {} reports sent", batch.count()),
+                Err(e) if e.is::<PermanentError>() => {
+                    error!("Unable to send the digest, giving up: {:#}", e)
+                }
+                Err(e) => {
+                    error!(
+                        "Unable to send the digest, retrying with the next one: {:#}",
+                        e
+                    );
+                    digest.restore(batch);
+                }
+            }
+        }
+    }
+
+    /// Send the digest at the end of every window
+    fn spawn_digest(&self, digest: Arc<Digest>) {
+        let mailer = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(digest.window);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes right away
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                if let Some(batch) = digest.take() {
+                    let mailer = mailer.clone();
+                    let digest = digest.clone();
+                    let sent = tokio::task::spawn_blocking(move || {
+                        mailer.send_digest_blocking(&digest, batch)
+                    })
+                    .await;
+
+                    if let Err(e) = sent {
+                        error!("Unable to send the digest: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Read an optional comma separated list of recipients
+fn recipient_list(env: &mut EnvReader, name: &str) -> Vec<Recipient> {
+    match env
+        .optional(name)
+        .map(|value| Recipient::parse_list(&value))
+    {
+        Some(Ok(recipients)) => recipients,
+        Some(Err(e)) => {
+            env.error(format!("{} is invalid: {}", name, e));
+            vec![]
+        }
+        None => vec![],
+    }
+}
+
+#[async_trait]
+impl Channel for Mailer {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let from_name = env.required("MORBO_MAILER_FROM_NAME");
+        let from_email = env.required("MORBO_MAILER_FROM_EMAIL");
+        let mut recipients = Recipients {
+            to: recipient_list(&mut env, "MORBO_MAILER_TO"),
+            cc: recipient_list(&mut env, "MORBO_MAILER_CC"),
+            bcc: recipient_list(&mut env, "MORBO_MAILER_BCC"),
+        };
+        if let Some(email) = env.optional("MORBO_MAILER_TO_EMAIL") {
+            let name = env.optional("MORBO_MAILER_TO_NAME");
+            match email.parse::<Recipient>() {
+                Ok(recipient) => recipients.to.insert(0, Recipient { name, ..recipient }),
+                Err(e) => env.error(format!("MORBO_MAILER_TO_EMAIL is invalid: {}", e)),
+            }
+        }
+
+        let routes = match env.optional("MORBO_MAILER_ROUTES") {
+            Some(path) => Routes::load(Path::new(&path)).unwrap_or_else(|e| {
+                env.error(format!("MORBO_MAILER_ROUTES is invalid: {:#}", e));
+                Routes::default()
+            }),
+            None => Routes::default(),
+        };
+        if recipients.is_empty() && routes.is_empty() && env.optional("MORBO_MAILER_TO").is_none() {
+            env.error("MORBO_MAILER_TO is missing");
+        }
+
+        let templates = match env.optional("MORBO_MAILER_TEMPLATES") {
+            Some(dir) => Templates::load(Some(Path::new(&dir))),
+            None => Templates::load(None),
+        }
+        .unwrap_or_else(|e| {
+            env.error(format!("MORBO_MAILER_TEMPLATES is invalid: {:#}", e));
+            // The built-in templates are always valid
+            Templates::load(None).expect("Invalid built-in templates")
+        });
+
+        let digest = env
+            .parse_optional::<DigestWindow>("MORBO_MAILER_DIGEST")
+            .map(|window| {
+                let notify_new = env
+                    .parse_optional("MORBO_MAILER_DIGEST_NOTIFY_NEW")
+                    .unwrap_or(false);
+                Arc::new(Digest::new(window.0, notify_new))
+            });
+
+        let smtp = Smtp::from_env(&mut env);
+
+        env.finish()?;
+
+        Ok(Mailer {
+            from_name,
+            from_email,
+            recipients,
+            routes: Arc::new(routes),
+            templates: Arc::new(templates),
+            digest,
+            smtp,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "mail"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        check_connection(&self.smtp.hostname, self.smtp.port).await
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let digest = match &self.digest {
+            Some(digest) => digest,
+            None => return self.send_report_in_background(report).await,
+        };
+
+        if digest.start() {
+            self.spawn_digest(digest.clone());
+        }
+
+        // The report is only added once its immediate email is sent, so a
+        // retried report is not counted twice. Once added, the report is
+        // acknowledged and only kept in memory until the end of the window,
+        // outbox or not.
+        if digest.notify_new && digest.is_new(report) {
+            self.send_report_in_background(report).await?;
+        }
+        digest.add(report);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::mailer::smtp::{SmtpAuth, SmtpSecurity};
+    use crate::csp::csp_report_content::CspReportContent;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn it_gives_up_on_servers_which_do_not_answer() {
+        // Connections are accepted, but the greeting never comes
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                sockets.push(listener.accept().await.unwrap());
+            }
+        });
+
+        let mailer = Mailer {
+            from_name: String::from("Morbo"),
+            from_email: String::from("morbo@example.org"),
+            recipients: Recipients {
+                to: Recipient::parse_list("security@example.org").unwrap(),
+                ..Default::default()
+            },
+            routes: Arc::default(),
+            templates: Arc::new(Templates::load(None).unwrap()),
+            digest: None,
+            smtp: Smtp {
+                hostname: String::from("127.0.0.1"),
+                port,
+                security: SmtpSecurity::None,
+                tls: None,
+                username: None,
+                password: None,
+                auth: SmtpAuth::Plain,
+                timeout: Duration::from_millis(100),
+            },
+        };
+        let report = ReceivedReport::new(CspReportContent::default(
+            "inline",
+            "https://shop.example.org/",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+
+        let error = mailer.send_report(&report).await.unwrap_err();
+
+        assert_eq!("Timeout sending the email to 127.0.0.1", error.to_string());
+    }
+}
@@ -0,0 +1,322 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use lettre::EmailAddress;
+use lettre_email::Mailbox;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::config::{self, ConfigFormat};
+use crate::csp::condition::{Condition, ConditionConfig};
+use crate::csp::received_report::ReceivedReport;
+
+/// An email address, with an optional display name
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Recipient {
+    pub name: Option<String>,
+    pub email: String,
+}
+
+impl Recipient {
+    /// Parse a comma separated list of recipients
+    pub fn parse_list(s: &str) -> Result<Vec<Recipient>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|recipient| !recipient.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = String;
+
+    /// Parse `email@example.org` or `Name <email@example.org>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, email) = match (s.rfind('<'), s.strip_suffix('>')) {
+            (Some(start), Some(rest)) => {
+                let name = s[..start].trim().trim_matches('"').trim();
+                let name = Some(name).filter(|name| !name.is_empty());
+                (name.map(String::from), rest[start + 1..].trim())
+            }
+            _ => (None, s),
+        };
+
+        EmailAddress::new(email.to_string())
+            .map_err(|_| format!("invalid email address {:?}", email))?;
+
+        Ok(Recipient {
+            name,
+            email: email.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Recipient {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<&Recipient> for Mailbox {
+    fn from(recipient: &Recipient) -> Self {
+        match &recipient.name {
+            Some(name) => Mailbox::new_with_name(name.clone(), recipient.email.clone()),
+            None => Mailbox::new(recipient.email.clone()),
+        }
+    }
+}
+
+/// The recipients of an email
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recipients {
+    pub to: Vec<Recipient>,
+    pub cc: Vec<Recipient>,
+    pub bcc: Vec<Recipient>,
+}
+
+impl Recipients {
+    pub fn is_empty(&self) -> bool {
+        self.to.is_empty() && self.cc.is_empty() && self.bcc.is_empty()
+    }
+
+    /// Add the recipients of another email, skipping the addresses which
+    /// already receive it
+    pub fn extend(&mut self, other: &Recipients) {
+        for (list, others) in [
+            (&mut self.to, &other.to),
+            (&mut self.cc, &other.cc),
+            (&mut self.bcc, &other.bcc),
+        ] {
+            list.extend(others.iter().cloned());
+        }
+
+        let mut seen = vec![];
+        for list in [&mut self.to, &mut self.cc, &mut self.bcc] {
+            list.retain(|recipient| {
+                let email = recipient.email.to_lowercase();
+                if seen.contains(&email) {
+                    false
+                } else {
+                    seen.push(email);
+                    true
+                }
+            });
+        }
+    }
+}
+
+/// The routes file, as written by the user
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoutesFile {
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+}
+
+/// A route, as written in the routes file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    name: String,
+
+    /// Projects whose reports are routed, defaults to every report
+    #[serde(default)]
+    projects: Vec<String>,
+
+    /// Condition on the report, defaults to every report
+    when: Option<ConditionConfig>,
+
+    #[serde(default)]
+    to: Vec<Recipient>,
+    #[serde(default)]
+    cc: Vec<Recipient>,
+    #[serde(default)]
+    bcc: Vec<Recipient>,
+}
+
+/// Recipients receiving the reports matching some conditions
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub projects: Vec<String>,
+    pub condition: Option<Condition>,
+    pub recipients: Recipients,
+}
+
+impl Route {
+    /// Test if a report is sent to the recipients of the route
+    pub fn matches(&self, report: &ReceivedReport) -> bool {
+        let project_matches = self.projects.is_empty()
+            || report
+                .project
+                .as_ref()
+                .is_some_and(|project| self.projects.contains(project));
+
+        project_matches
+            && self
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.evaluate(&report.csp_report))
+    }
+}
+
+/// The routes of the mail channel
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    /// Load the routes from a TOML or YAML file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        config::load(path, Self::parse)
+    }
+
+    /// Parse the content of a routes file
+    pub fn parse(content: &str, format: ConfigFormat) -> Result<Self, Error> {
+        let file: RoutesFile = format.parse(content)?;
+
+        let routes = file
+            .routes
+            .into_iter()
+            .map(|route| {
+                let name = route.name;
+                let condition = route
+                    .when
+                    .map(ConditionConfig::compile)
+                    .transpose()
+                    .with_context(|| format!("Invalid route {:?}", name))?;
+                let recipients = Recipients {
+                    to: route.to,
+                    cc: route.cc,
+                    bcc: route.bcc,
+                };
+                if recipients.is_empty() {
+                    bail!("Route {:?} has no recipient", name);
+                }
+
+                Ok(Route {
+                    name,
+                    projects: route.projects,
+                    condition,
+                    recipients,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Routes { routes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The recipients of a report: those of every matching route, or the
+    /// default ones when no route matches
+    pub fn recipients(&self, report: &ReceivedReport, default: &Recipients) -> Recipients {
+        let mut recipients = Recipients::default();
+        let mut matched = false;
+
+        for route in self.routes.iter().filter(|route| route.matches(report)) {
+            debug!("Report routed by {:?}", route.name);
+            recipients.extend(&route.recipients);
+            matched = true;
+        }
+
+        if matched {
+            recipients
+        } else {
+            default.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    fn report(document_uri: &str, project: Option<&str>) -> ReceivedReport {
+        let mut report = ReceivedReport::new(CspReportContent::default(
+            "https://evil.example.com/x.js",
+            document_uri,
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+        report.project = project.map(String::from);
+        report
+    }
+
+    fn recipients(to: &[&str]) -> Recipients {
+        Recipients {
+            to: to.iter().map(|to| to.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_parses_recipients() {
+        assert_eq!(
+            Ok(vec![
+                Recipient {
+                    name: Some(String::from("Security Team")),
+                    email: String::from("security@example.org"),
+                },
+                Recipient {
+                    name: None,
+                    email: String::from("ops@example.org"),
+                },
+            ]),
+            Recipient::parse_list("\"Security Team\" <security@example.org>, ops@example.org,")
+        );
+        assert!(Recipient::parse_list("Ops <ops>").is_err());
+    }
+
+    #[test]
+    fn it_routes_reports_to_matching_recipients() {
+        let routes = Routes::parse(
+            r#"
+            [[routes]]
+            name = "payments"
+            when = { field = "document_uri", pattern = "https://shop.example.org/checkout", mode = "prefix" }
+            to = ["Payments <payments@example.org>"]
+            cc = ["security@example.org"]
+
+            [[routes]]
+            name = "blog"
+            projects = ["blog"]
+            to = ["blog@example.org"]
+            bcc = ["security@example.org"]
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        let default = recipients(&["ops@example.org"]);
+
+        let checkout = report("https://shop.example.org/checkout/pay", Some("shop"));
+        let mut expected = recipients(&["Payments <payments@example.org>"]);
+        expected.cc = vec!["security@example.org".parse().unwrap()];
+        assert_eq!(expected, routes.recipients(&checkout, &default));
+
+        let blog = report("https://blog.example.org/checkout", Some("blog"));
+        let mut expected = recipients(&["blog@example.org"]);
+        expected.bcc = vec!["security@example.org".parse().unwrap()];
+        assert_eq!(expected, routes.recipients(&blog, &default));
+
+        let home = report("https://shop.example.org/", Some("shop"));
+        assert_eq!(default, routes.recipients(&home, &default));
+    }
+
+    #[test]
+    fn it_rejects_routes_without_recipients() {
+        let routes = Routes::parse("[[routes]]\nname = \"nobody\"", ConfigFormat::Toml);
+        assert!(routes.is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, SmtpTransport};
+use native_tls::{Certificate, TlsConnector};
+
+use crate::channel::support::EnvReader;
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain text connection
+    None,
+
+    /// STARTTLS when the server supports it, plain text otherwise
+    Opportunistic,
+
+    /// STARTTLS, failing when the server does not support it
+    StartTls,
+
+    /// TLS from the start of the connection
+    Tls,
+}
+
+impl SmtpSecurity {
+    /// The usual port of the security mode
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::None => 25,
+            SmtpSecurity::Opportunistic | SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+        }
+    }
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SmtpSecurity::None),
+            "opportunistic" => Ok(SmtpSecurity::Opportunistic),
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            _ => Err(String::from(
+                "expected none, opportunistic, starttls or tls",
+            )),
+        }
+    }
+}
+
+/// The authentication mechanism used with the SMTP server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpAuth {
+    Plain,
+    Login,
+}
+
+impl FromStr for SmtpAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(SmtpAuth::Plain),
+            "login" => Ok(SmtpAuth::Login),
+            _ => Err(String::from("expected plain or login")),
+        }
+    }
+}
+
+impl From<SmtpAuth> for Mechanism {
+    fn from(auth: SmtpAuth) -> Self {
+        match auth {
+            SmtpAuth::Plain => Mechanism::Plain,
+            SmtpAuth::Login => Mechanism::Login,
+        }
+    }
+}
+
+/// The SMTP server the emails are sent through
+#[derive(Clone)]
+pub struct Smtp {
+    pub hostname: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+
+    /// The TLS connector, trusting the custom CA bundle if any
+    pub tls: Option<TlsConnector>,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub auth: SmtpAuth,
+
+    /// Maximum time to send an email, from the connection to the last
+    /// command
+    pub timeout: Duration,
+}
+
+/// Build a TLS connector trusting the system roots, and the certificates of
+/// a PEM bundle if any
+fn tls_connector(ca_file: Option<&str>) -> Result<TlsConnector, Error> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(ca_file) = ca_file {
+        let bundle =
+            fs::read_to_string(ca_file).with_context(|| format!("Unable to read {}", ca_file))?;
+        let mut certificates = 0;
+
+        for pem in bundle.split_inclusive("-----END CERTIFICATE-----") {
+            if !pem.contains("-----BEGIN CERTIFICATE-----") {
+                continue;
+            }
+
+            let certificate = Certificate::from_pem(pem.trim().as_bytes())
+                .with_context(|| format!("Invalid certificate in {}", ca_file))?;
+            builder.add_root_certificate(certificate);
+            certificates += 1;
+        }
+
+        if certificates == 0 {
+            bail!("No certificate found in {}", ca_file);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+impl Smtp {
+    /// Read the SMTP settings from the `MORBO_MAILER_SMTP_*` variables
+    pub fn from_env(env: &mut EnvReader) -> Self {
+        let hostname = env.required("MORBO_MAILER_SMTP_HOSTNAME");
+        let security = env
+            .parse_optional("MORBO_MAILER_SMTP_SECURITY")
+            .unwrap_or(SmtpSecurity::None);
+        let port = env
+            .parse_optional("MORBO_MAILER_SMTP_PORT")
+            .unwrap_or_else(|| security.default_port());
+
+        let tls = match security {
+            SmtpSecurity::None => None,
+            _ => {
+                let ca_file = env.optional("MORBO_MAILER_SMTP_CA_FILE");
+
+                match tls_connector(ca_file.as_deref()) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => {
+                        env.error(format!("MORBO_MAILER_SMTP_CA_FILE is invalid: {:#}", e));
+                        None
+                    }
+                }
+            }
+        };
+
+        // Authentication is optional, but needs both variables
+        let username = env.optional("MORBO_MAILER_SMTP_USERNAME");
+        let password = env.optional("MORBO_MAILER_SMTP_PASSWORD");
+
+        if username.is_some() != password.is_some() {
+            env.error(
+                "MORBO_MAILER_SMTP_USERNAME and MORBO_MAILER_SMTP_PASSWORD must be set together",
+            );
+        }
+
+        // The password would be sent in plain text
+        if username.is_some() && security == SmtpSecurity::None {
+            env.error("MORBO_MAILER_SMTP_USERNAME needs MORBO_MAILER_SMTP_SECURITY to be opportunistic, starttls or tls");
+        }
+
+        let auth = env
+            .parse_optional("MORBO_MAILER_SMTP_AUTH")
+            .unwrap_or(SmtpAuth::Plain);
+        let timeout = env
+            .parse_optional("MORBO_MAILER_SMTP_TIMEOUT")
+            .map_or(Duration::from_secs(30), Duration::from_secs);
+
+        Smtp {
+            hostname,
+            port,
+            security,
+            tls,
+            username,
+            password,
+            auth,
+            timeout,
+        }
+    }
+
+    fn client_security(&self) -> ClientSecurity {
+        let tls = match &self.tls {
+            Some(tls) => ClientTlsParameters::new(self.hostname.clone(), tls.clone()),
+            None => return ClientSecurity::None,
+        };
+
+        match self.security {
+            SmtpSecurity::None => ClientSecurity::None,
+            SmtpSecurity::Opportunistic => ClientSecurity::Opportunistic(tls),
+            SmtpSecurity::StartTls => ClientSecurity::Required(tls),
+            SmtpSecurity::Tls => ClientSecurity::Wrapper(tls),
+        }
+    }
+
+    pub fn transport(&self) -> Result<SmtpTransport, Error> {
+        let addr = (self.hostname.as_str(), self.port);
+
+        let mut smtp_client =
+            SmtpClient::new(addr, self.client_security())?.timeout(Some(self.timeout));
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            let credentials = Credentials::new(username.to_owned(), password.to_owned());
+
+            smtp_client = smtp_client
+                .credentials(credentials)
+                .authentication_mechanism(self.auth.into());
+        }
+
+        Ok(smtp_client.transport())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_parses_security_modes() {
+        assert_eq!(Ok(SmtpSecurity::StartTls), "starttls".parse());
+        assert_eq!(465, "tls".parse::<SmtpSecurity>().unwrap().default_port());
+        assert!("ssl".parse::<SmtpSecurity>().is_err());
+        assert_eq!(Ok(SmtpAuth::Login), "login".parse());
+    }
+
+    #[test]
+    fn it_rejects_ca_bundles_without_certificates() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let error = tls_connector(file.path().to_str()).unwrap_err();
+
+        assert!(error.to_string().starts_with("No certificate found"));
+    }
+
+    #[test]
+    fn it_refuses_credentials_without_tls() {
+        env::set_var("MORBO_MAILER_SMTP_HOSTNAME", "smtp.example.org");
+        env::set_var("MORBO_MAILER_SMTP_USERNAME", "morbo");
+        env::set_var("MORBO_MAILER_SMTP_PASSWORD", "secret");
+
+        let mut env = EnvReader::default();
+        Smtp::from_env(&mut env);
+
+        assert_eq!(
+            "MORBO_MAILER_SMTP_USERNAME needs MORBO_MAILER_SMTP_SECURITY to be opportunistic, starttls or tls",
+            env.finish().unwrap_err().to_string()
+        );
+    }
+}
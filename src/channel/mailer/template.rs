@@ -0,0 +1,306 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use minijinja::Environment;
+use serde::Serialize;
+use url::Url;
+
+use crate::channel::mailer::digest::DigestBatch;
+use crate::csp::condition::host;
+use crate::csp::received_report::ReceivedReport;
+
+/// The templates of an email, and their built-in content
+const TEMPLATES: &[(&str, &str)] = &[
+    ("subject.txt", include_str!("templates/subject.txt")),
+    ("report.txt", include_str!("templates/report.txt")),
+    ("report.html", include_str!("templates/report.html")),
+    (
+        "digest_subject.txt",
+        include_str!("templates/digest_subject.txt"),
+    ),
+    ("digest.txt", include_str!("templates/digest.txt")),
+    ("digest.html", include_str!("templates/digest.html")),
+];
+
+/// A line of the table of report fields
+#[derive(Debug, Serialize)]
+struct TemplateField {
+    name: &'static str,
+    value: String,
+
+    /// The value, when it can be opened in a browser
+    link: Option<String>,
+}
+
+/// The variables available in the templates
+#[derive(Debug, Serialize)]
+struct TemplateContext<'a> {
+    report: &'a ReceivedReport,
+    fields: Vec<TemplateField>,
+    directive: &'a str,
+    blocked_host: String,
+    document_host: Option<String>,
+    document_link: Option<String>,
+    project: Option<&'a str>,
+}
+
+/// A violation of a digest, as available in the templates
+#[derive(Debug, Serialize)]
+struct DigestEntryContext<'a> {
+    directive: &'a str,
+    blocked_uri: &'a str,
+    document_host: Option<&'a str>,
+    document_link: Option<String>,
+    count: u64,
+    first_seen: String,
+    last_seen: String,
+    report: &'a ReceivedReport,
+}
+
+/// The variables available in the digest templates
+#[derive(Debug, Serialize)]
+struct DigestContext<'a> {
+    entries: Vec<DigestEntryContext<'a>>,
+    count: u64,
+    since: String,
+    until: String,
+    project: Option<&'a str>,
+}
+
+/// An email rendered from the templates
+#[derive(Debug, PartialEq, Eq)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// The URI, if it can be safely linked to
+fn link(uri: &str) -> Option<String> {
+    Url::parse(uri)
+        .ok()
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .map(String::from)
+}
+
+/// A time, as displayed in the emails
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+/// The templates of the report emails
+#[derive(Debug)]
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    /// Load the templates, the ones found in the given directory replacing
+    /// the built-in ones
+    pub fn load(dir: Option<&Path>) -> Result<Self, Error> {
+        let mut env = Environment::new();
+
+        for (name, builtin) in TEMPLATES {
+            let source = match dir.map(|dir| dir.join(name)).filter(|path| path.exists()) {
+                Some(path) => fs::read_to_string(&path)
+                    .with_context(|| format!("Unable to read {}", path.display()))?,
+                None => builtin.to_string(),
+            };
+
+            env.add_template_owned(*name, source)
+                .with_context(|| format!("Invalid template {}", name))?;
+        }
+
+        Ok(Templates { env })
+    }
+
+    fn context<'a>(report: &'a ReceivedReport) -> TemplateContext<'a> {
+        let csp_report = &report.csp_report;
+        let directive = csp_report
+            .effective_directive
+            .as_deref()
+            .unwrap_or(&csp_report.violated_directive);
+
+        let mut fields = vec![];
+        let mut add = |name, value: Option<&str>| {
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                fields.push(TemplateField {
+                    name,
+                    value: value.to_string(),
+                    link: link(value),
+                });
+            }
+        };
+        add("Document URI", Some(&csp_report.document_uri));
+        add("Blocked URI", Some(&csp_report.blocked_uri));
+        add("Violated directive", Some(&csp_report.violated_directive));
+        add(
+            "Effective directive",
+            csp_report.effective_directive.as_deref(),
+        );
+        add("Disposition", csp_report.disposition.as_deref());
+        add("Location", csp_report.location().as_deref());
+        add("Script sample", csp_report.script_sample.as_deref());
+        add("Status code", csp_report.status_code.as_deref());
+        add("Referrer", Some(&csp_report.referrer));
+        add("Original policy", Some(&csp_report.original_policy));
+        if report.duplicates > 0 {
+            let occurrences = format!("{} times", report.duplicates + 1);
+            add("Occurrences", Some(&occurrences));
+        }
+        add("Project", report.project.as_deref());
+        add("User-Agent", report.user_agent.as_deref());
+        add("Received at", Some(&report.received_at.to_rfc3339()));
+
+        TemplateContext {
+            report,
+            fields,
+            directive,
+            blocked_host: host(&csp_report.blocked_uri)
+                .unwrap_or_else(|| csp_report.blocked_uri.clone()),
+            document_host: host(&csp_report.document_uri),
+            document_link: link(&csp_report.document_uri),
+            project: report.project.as_deref(),
+        }
+    }
+
+    fn render_template<C: Serialize>(&self, name: &str, context: &C) -> Result<String, Error> {
+        self.env
+            .get_template(name)?
+            .render(context)
+            .with_context(|| format!("Unable to render template {}", name))
+    }
+
+    /// Render the email of a report
+    pub fn render(&self, report: &ReceivedReport) -> Result<RenderedEmail, Error> {
+        let context = Self::context(report);
+        let subject = self.render_template("subject.txt", &context)?;
+
+        Ok(RenderedEmail {
+            // The subject must fit on a single header line
+            subject: subject.split_whitespace().collect::<Vec<_>>().join(" "),
+            text: self.render_template("report.txt", &context)?,
+            html: self.render_template("report.html", &context)?,
+        })
+    }
+
+    /// Render the digest email of the violations reported during a window
+    pub fn render_digest(&self, batch: &DigestBatch) -> Result<RenderedEmail, Error> {
+        let entries = batch
+            .entries
+            .iter()
+            .map(|entry| DigestEntryContext {
+                directive: &entry.key.directive,
+                blocked_uri: &entry.key.blocked_uri,
+                document_host: entry.key.document_host.as_deref(),
+                document_link: link(&entry.report.csp_report.document_uri),
+                count: entry.count,
+                first_seen: format_time(entry.first_seen),
+                last_seen: format_time(entry.last_seen),
+                report: &entry.report,
+            })
+            .collect();
+        let context = DigestContext {
+            entries,
+            count: batch.count(),
+            since: format_time(batch.since),
+            until: format_time(batch.until),
+            project: batch
+                .entries
+                .first()
+                .and_then(|entry| entry.report.project.as_deref()),
+        };
+        let subject = self.render_template("digest_subject.txt", &context)?;
+
+        Ok(RenderedEmail {
+            subject: subject.split_whitespace().collect::<Vec<_>>().join(" "),
+            text: self.render_template("digest.txt", &context)?,
+            html: self.render_template("digest.html", &context)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::mailer::digest::Digest;
+    use crate::csp::csp_report_content::CspReportContent;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn report() -> ReceivedReport {
+        let mut csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://shop.example.org/cart?a=1&b=<2>",
+            "default-src 'self'",
+            "",
+            "script-src",
+        );
+        csp_report.effective_directive = Some(String::from("script-src-elem"));
+        let mut report = ReceivedReport::new(csp_report);
+        report.project = Some(String::from("shop"));
+        report
+    }
+
+    #[test]
+    fn it_renders_the_builtin_templates() {
+        let email = Templates::load(None).unwrap().render(&report()).unwrap();
+
+        assert_eq!(
+            "[CSP] script-src-elem blocked evil.example.com on shop.example.org (shop)",
+            email.subject
+        );
+        assert!(email
+            .text
+            .contains("Document URI: https://shop.example.org/cart?a=1&b=<2>\n"));
+        assert!(!email.text.contains("Referrer"));
+        // Values are escaped, and only http(s) URIs are linked
+        assert_eq!(None, link("javascript:alert(1)"));
+        assert!(email.html.contains("on <a href=\""));
+        assert!(email.html.contains("b=&lt;2&gt;"));
+        assert!(!email.html.contains("<2>"));
+    }
+
+    #[test]
+    fn it_renders_digests() {
+        let digest = Digest::new(Duration::from_secs(60), false);
+        digest.add(&report());
+        digest.add(&report());
+        let batch = digest.take().unwrap();
+
+        let email = Templates::load(None)
+            .unwrap()
+            .render_digest(&batch)
+            .unwrap();
+
+        assert_eq!("[CSP] 2 reports of 1 violation (shop)", email.subject);
+        assert!(email.text.contains(
+            "2 × script-src-elem blocked https://evil.example.com/x.js on shop.example.org\n"
+        ));
+    }
+
+    #[test]
+    fn it_overrides_templates_from_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut subject = fs::File::create(dir.path().join("subject.txt")).unwrap();
+        writeln!(subject, "CSP: {{{{ report.csp_report.blocked_uri }}}}").unwrap();
+
+        let email = Templates::load(Some(dir.path()))
+            .unwrap()
+            .render(&report())
+            .unwrap();
+
+        assert_eq!("CSP: https://evil.example.com/x.js", email.subject);
+        assert!(email.text.starts_with("New CSP violation on project shop"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("report.html"), "{% if %}").unwrap();
+
+        assert!(Templates::load(Some(dir.path())).is_err());
+    }
+}
@@ -1,17 +1,250 @@
+#[cfg(feature = "chat")]
+pub mod chat;
+
+#[cfg(any(feature = "chat", feature = "sentry", feature = "webhook"))]
+pub mod http;
+
+#[cfg(feature = "file")]
+pub mod file;
+
 #[cfg(feature = "mail")]
 pub mod mailer;
 
 #[cfg(feature = "sentry")]
 pub mod sentry;
 
-use crate::csp::csp_report_content::CspReportContent;
-use anyhow::Error;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(feature = "syslog")]
+pub mod syslog;
+
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+pub mod support;
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::csp::received_report::ReceivedReport;
+use crate::project::{Project, Projects};
+use anyhow::{bail, Error};
+use async_trait::async_trait;
+use tracing::info;
+
+/// An error which retrying will not fix, like an address rejected by the
+/// SMTP server. Channels wrap such errors so they are not retried:
+/// `Error::new(PermanentError(e.into()))`.
+#[derive(Debug)]
+pub struct PermanentError(pub Error);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
 
 /// A channel represents how a CSP report is sent
-pub trait Channel<T> {
-    /// Construct a channel from environment variables
-    fn load_from_env() -> Self;
+#[async_trait]
+pub trait Channel: Send + Sync {
+    /// Construct a channel from environment variables, reporting every
+    /// missing or invalid one
+    #[cfg_attr(
+        not(any(
+            feature = "discord",
+            feature = "file",
+            feature = "mail",
+            feature = "sentry",
+            feature = "slack",
+            feature = "sqlite",
+            feature = "syslog",
+            feature = "teams",
+            feature = "webhook"
+        )),
+        allow(dead_code)
+    )]
+    fn load_from_env() -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// The name of the channel, used in logs
+    fn name(&self) -> &'static str;
+
+    /// Whether the channel also receives the reports dropped by the filters
+    fn accepts_filtered(&self) -> bool {
+        false
+    }
+
+    /// Check that the channel can reach its service, used by
+    /// `--check-channels`
+    async fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
 
     /// Implements how the channel is sending the report
-    fn send_report(&self, report: &CspReportContent) -> Result<T, Error>;
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error>;
+}
+
+/// Builds a channel from the environment, with the settings of a project
+/// if any
+type Loader = fn(Option<&Project>) -> Result<Arc<dyn Channel>, Error>;
+
+/// The name and the loader of every enabled channel
+const LOADERS: &[(&str, Loader)] = &[
+    #[cfg(feature = "discord")]
+    ("discord", |project| {
+        let mut discord = chat::discord::Discord::load_from_env()?;
+        if let Some(config) = project.and_then(|project| project.discord.as_ref()) {
+            config.apply(&mut discord.webhook)?;
+        }
+        Ok(Arc::new(discord))
+    }),
+    #[cfg(feature = "file")]
+    ("file", |_| Ok(Arc::new(file::JsonLines::load_from_env()?))),
+    #[cfg(feature = "mail")]
+    ("mail", |project| {
+        let mut mailer = mailer::Mailer::load_from_env()?;
+        if let Some(mail) = project.and_then(|project| project.mail.as_ref()) {
+            mail.apply(&mut mailer)?;
+        }
+        Ok(Arc::new(mailer))
+    }),
+    #[cfg(feature = "sentry")]
+    ("sentry", |project| {
+        let mut sentry = sentry::Sentry::load_from_env()?;
+        if let Some(config) = project.and_then(|project| project.sentry.as_ref()) {
+            config.apply(&mut sentry)?;
+        }
+        Ok(Arc::new(sentry))
+    }),
+    #[cfg(feature = "slack")]
+    ("slack", |project| {
+        let mut slack = chat::slack::Slack::load_from_env()?;
+        if let Some(config) = project.and_then(|project| project.slack.as_ref()) {
+            config.apply(&mut slack)?;
+        }
+        Ok(Arc::new(slack))
+    }),
+    #[cfg(feature = "sqlite")]
+    ("sqlite", |_| Ok(Arc::new(sqlite::Sqlite::load_from_env()?))),
+    #[cfg(feature = "syslog")]
+    ("syslog", |_| Ok(Arc::new(syslog::Syslog::load_from_env()?))),
+    #[cfg(feature = "teams")]
+    ("teams", |project| {
+        let mut teams = chat::teams::Teams::load_from_env()?;
+        if let Some(config) = project.and_then(|project| project.teams.as_ref()) {
+            config.apply(&mut teams.webhook)?;
+        }
+        Ok(Arc::new(teams))
+    }),
+    #[cfg(feature = "webhook")]
+    ("webhook", |project| {
+        let mut webhook = webhook::Webhook::load_from_env()?;
+        if let Some(config) = project.and_then(|project| project.webhook.as_ref()) {
+            config.apply(&mut webhook)?;
+        }
+        Ok(Arc::new(webhook))
+    }),
+];
+
+/// Build the enabled channels, with the settings of a project if any.
+/// Errors are added to the given list, so every misconfigured channel can
+/// be reported at once.
+fn load_channels(project: Option<&Project>, errors: &mut Vec<Error>) -> Vec<Arc<dyn Channel>> {
+    let mut channels = vec![];
+
+    for (name, load) in LOADERS {
+        match load(project) {
+            Ok(channel) => channels.push(channel),
+            Err(e) => errors.push(e.context(match project {
+                Some(project) => format!("Invalid {} channel of project {}", name, project.slug),
+                None => format!("Invalid {} channel", name),
+            })),
+        }
+    }
+
+    channels
+}
+
+/// The channels of the global endpoint and of every project, built once at
+/// startup
+pub struct Channels {
+    pub global: Vec<Arc<dyn Channel>>,
+    pub projects: Vec<(String, Vec<Arc<dyn Channel>>)>,
+}
+
+impl Channels {
+    /// Build every channel, failing with the list of all the invalid ones
+    pub fn load(projects: &Projects) -> Result<Self, Error> {
+        let mut errors = vec![];
+
+        let global = load_channels(None, &mut errors);
+        let projects = projects
+            .iter()
+            .map(|project| {
+                let channels = load_channels(Some(project), &mut errors);
+                (project.slug.clone(), channels)
+            })
+            .collect();
+
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors.iter().map(|e| format!("  - {:#}", e)).collect();
+            bail!("Invalid channel configuration:\n{}", errors.join("\n"));
+        }
+
+        let channels = Channels { global, projects };
+        channels.log();
+
+        Ok(channels)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Option<&str>, &Arc<dyn Channel>)> {
+        let global = self.global.iter().map(|channel| (None, channel));
+        let projects = self.projects.iter().flat_map(|(slug, channels)| {
+            channels
+                .iter()
+                .map(move |channel| (Some(slug.as_str()), channel))
+        });
+
+        global.chain(projects)
+    }
+
+    fn log(&self) {
+        let names: Vec<&str> = self.global.iter().map(|channel| channel.name()).collect();
+        info!("Enabled channels: {}", names.join(", "));
+
+        for (slug, channels) in &self.projects {
+            let names: Vec<&str> = channels.iter().map(|channel| channel.name()).collect();
+            info!("Enabled channels of project {}: {}", slug, names.join(", "));
+        }
+    }
+
+    /// Check that every channel can reach its service, failing with the
+    /// list of all the unreachable ones
+    pub async fn check(&self) -> Result<(), Error> {
+        let mut errors = vec![];
+
+        for (project, channel) in self.iter() {
+            if let Err(e) = channel.check().await {
+                errors.push(match project {
+                    Some(project) => format!(
+                        "  - {} channel of project {}: {:#}",
+                        channel.name(),
+                        project,
+                        e
+                    ),
+                    None => format!("  - {} channel: {:#}", channel.name(), e),
+                });
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("Unreachable channels:\n{}", errors.join("\n"));
+        }
+
+        Ok(())
+    }
 }
@@ -1,32 +1,344 @@
+use crate::channel::http::check_status;
+use crate::channel::support::{check_connection, EnvReader};
 use crate::channel::Channel;
-use crate::csp::csp_report_content::CspReportContent;
-use anyhow::Error;
-use sentry_core;
-use sentry_core::types::Uuid;
-use std::env;
+use crate::csp::condition::host;
+use crate::csp::received_report::ReceivedReport;
+use anyhow::{Context, Error};
+use async_trait::async_trait;
+use sentry_core::protocol::{Context as SentryContext, Envelope, Event, Level, Map, Request};
+use sentry_core::types::{Dsn, Uuid};
+use sentry_core::{Client, ClientOptions, Transport};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tracing::debug;
+
+/// Maximum time to send an event
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps the envelopes of the events prepared by the client, so the
+/// channel sends them itself and sees the answer of Sentry
+#[derive(Default)]
+struct Captured(Mutex<Vec<Envelope>>);
+
+impl Captured {
+    /// Take the envelope of an event
+    fn take(&self, uuid: Uuid) -> Option<Envelope> {
+        let mut envelopes = self.0.lock().unwrap();
+        let index = envelopes
+            .iter()
+            .position(|envelope| envelope.uuid() == Some(&uuid))?;
+
+        Some(envelopes.remove(index))
+    }
+}
+
+impl Transport for Captured {
+    fn send_envelope(&self, envelope: Envelope) {
+        self.0.lock().unwrap().push(envelope);
+    }
+}
 
 /// Sentry channel
+#[derive(Clone)]
 pub struct Sentry {
     pub dsn: String,
+    pub level: Level,
+    pub environment: Option<String>,
+    pub release: Option<String>,
+
+    /// The client, created on the first report, once the project settings
+    /// are applied
+    client: Arc<OnceLock<Client>>,
+    captured: Arc<Captured>,
+    http: reqwest::Client,
 }
 
-impl Channel<Uuid> for Sentry {
-    fn load_from_env() -> Self {
-        let dsn = env::var("MORBO_SENTRY_DSN").unwrap();
+impl Sentry {
+    /// Check that a DSN can be parsed, as the client panics on invalid ones
+    pub fn validate_dsn(dsn: &str) -> Result<(), Error> {
+        dsn.parse::<Dsn>()
+            .with_context(|| format!("Invalid Sentry DSN {:?}", dsn))?;
+
+        Ok(())
+    }
+
+    fn client(&self) -> &Client {
+        self.client.get_or_init(|| {
+            let options = ClientOptions {
+                dsn: self.dsn.parse().ok(),
+                environment: self.environment.clone().map(Cow::Owned),
+                release: self.release.clone().map(Cow::Owned),
+                // Only keep the event processing, not the panic handler
+                // and such
+                default_integrations: false,
+                transport: Some(Arc::new(self.captured.clone())),
+                ..Default::default()
+            };
+
+            Client::from(sentry_core::apply_defaults(options))
+        })
+    }
+
+    /// Build the event of a report. Events are grouped by directive,
+    /// blocked host and document host.
+    pub fn event(&self, report: &ReceivedReport) -> Event<'static> {
+        let csp_report = &report.csp_report;
+        let directive = csp_report
+            .effective_directive
+            .as_deref()
+            .unwrap_or(&csp_report.violated_directive);
+        let blocked =
+            host(&csp_report.blocked_uri).unwrap_or_else(|| csp_report.blocked_uri.clone());
+        let document_host = host(&csp_report.document_uri);
+
+        let mut tags = Map::new();
+        tags.insert(
+            String::from("violated_directive"),
+            csp_report.violated_directive.clone(),
+        );
+        if let Some(effective_directive) = &csp_report.effective_directive {
+            tags.insert(
+                String::from("effective_directive"),
+                effective_directive.clone(),
+            );
+        }
+        if let Some(disposition) = &csp_report.disposition {
+            tags.insert(String::from("disposition"), disposition.clone());
+        }
+        tags.insert(String::from("blocked_host"), blocked.clone());
+        if let Some(document_host) = &document_host {
+            tags.insert(String::from("document_host"), document_host.clone());
+        }
+        if let Some(project) = &report.project {
+            tags.insert(String::from("project"), project.clone());
+        }
+
+        let mut contexts = Map::new();
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(csp_report) {
+            let fields = fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .collect();
+            contexts.insert(String::from("csp"), SentryContext::Other(fields));
+        }
 
-        Sentry { dsn }
+        let mut extra = Map::new();
+        if let Some(location) = csp_report.location() {
+            extra.insert(String::from("location"), serde_json::Value::from(location));
+        }
+        if let Some(filtered_by) = &report.filtered_by {
+            extra.insert(
+                String::from("filtered_by"),
+                serde_json::Value::from(filtered_by.clone()),
+            );
+        }
+
+        if report.duplicates > 0 {
+            extra.insert(
+                String::from("duplicates"),
+                serde_json::Value::from(report.duplicates),
+            );
+        }
+        if let Some(fingerprint) = &report.fingerprint {
+            extra.insert(
+                String::from("fingerprint"),
+                serde_json::Value::from(fingerprint.clone()),
+            );
+        }
+
+        let mut headers = Map::new();
+        if let Some(user_agent) = &report.user_agent {
+            headers.insert(String::from("User-Agent"), user_agent.clone());
+        }
+
+        let fingerprint = vec![
+            Cow::Borrowed("csp"),
+            Cow::Owned(directive.to_string()),
+            Cow::Owned(blocked.clone()),
+            Cow::Owned(document_host.unwrap_or_default()),
+        ];
+
+        Event {
+            message: Some(format!("CSP violation: {} blocked {}", directive, blocked)),
+            level: self.level,
+            logger: Some(String::from("morbo")),
+            fingerprint: Cow::Owned(fingerprint),
+            timestamp: report.received_at.into(),
+            request: Some(Request {
+                url: csp_report.document_uri.parse().ok(),
+                headers,
+                ..Default::default()
+            }),
+            tags,
+            contexts,
+            extra,
+            ..Default::default()
+        }
     }
+}
+
+#[async_trait]
+impl Channel for Sentry {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+        let dsn = env.required("MORBO_SENTRY_DSN");
+        let level = env.parse_optional("MORBO_SENTRY_LEVEL");
+        let environment = env.optional("MORBO_SENTRY_ENVIRONMENT");
+        let release = env.optional("MORBO_SENTRY_RELEASE");
+        env.finish()?;
+
+        Self::validate_dsn(&dsn)?;
+
+        let http = reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .context("Unable to create the HTTP client")?;
+
+        Ok(Sentry {
+            dsn,
+            level: level.unwrap_or(Level::Info),
+            environment,
+            release,
+            client: Arc::default(),
+            captured: Arc::default(),
+            http,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "sentry"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let dsn: Dsn = self.dsn.parse()?;
+
+        check_connection(dsn.host(), dsn.port()).await
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let client = self.client();
+        let uuid = client.capture_event(self.event(report), None);
+        // Nothing to send when the client drops the event
+        let envelope = match self.captured.take(uuid) {
+            Some(envelope) => envelope,
+            None => return Ok(()),
+        };
+
+        let mut body = vec![];
+        envelope.to_writer(&mut body)?;
+
+        let dsn: Dsn = self.dsn.parse()?;
+        let url = dsn.envelope_api_url();
+        let auth = dsn.to_auth(Some(&client.options().user_agent));
+        let response = self
+            .http
+            .post(url.as_str())
+            .header("X-Sentry-Auth", auth.to_string())
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Unable to send the event to {}", url))?;
+        check_status(url.as_str(), response.status())?;
+        debug!("Sentry event sent: {}", uuid);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::PermanentError;
+    use crate::csp::csp_report_content::CspReportContent;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn sentry() -> Sentry {
+        Sentry {
+            dsn: String::from("https://key@sentry.example.com/1"),
+            level: Level::Warning,
+            environment: None,
+            release: None,
+            client: Arc::default(),
+            captured: Arc::default(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    #[test]
+    fn it_groups_events_by_directive_and_hosts() {
+        let mut csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js?v=1",
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src-elem",
+        );
+        csp_report.disposition = Some(String::from("enforce"));
+        let mut report = ReceivedReport::new(csp_report);
+        report.project = Some(String::from("shop"));
+
+        let event = sentry().event(&report);
+
+        assert_eq!(Level::Warning, event.level);
+        assert_eq!(
+            vec![
+                "csp",
+                "script-src-elem",
+                "evil.example.com",
+                "shop.example.org"
+            ],
+            event
+                .fingerprint
+                .iter()
+                .map(|part| part.as_ref())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("evil.example.com", event.tags["blocked_host"]);
+        assert_eq!("shop.example.org", event.tags["document_host"]);
+        assert_eq!("enforce", event.tags["disposition"]);
+        assert_eq!("shop", event.tags["project"]);
+        assert!(event.contexts.contains_key("csp"));
+    }
+
+    #[tokio::test]
+    async fn it_fails_when_sentry_rejects_the_event() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sentry = sentry();
+        sentry.dsn = format!("http://key@{}/1", listener.local_addr().unwrap());
 
-    fn send_report(&self, report: &CspReportContent) -> Result<Uuid, Error> {
-        let dsn = self.dsn.as_str();
-        let _guard = sentry_core::init(dsn);
-        let json_report = serde_json::to_string_pretty(report).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            // Read until the end of the envelope, its last line being the
+            // event
+            while !request.ends_with(b"}") && !request.ends_with(b"}\n") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            socket
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
 
-        let message = format!("CSP alert from {}\n{}", report.document_uri, json_report);
+        let report = ReceivedReport::new(CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src-elem",
+        ));
+        let error = sentry.send_report(&report).await.unwrap_err();
 
-        Ok(sentry_core::capture_message(
-            &*message,
-            sentry_core::Level::Info,
-        ))
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /api/1/envelope/ HTTP/1.1"));
+        assert!(request.contains("sentry_key=key"));
+        assert!(error.to_string().contains("500 Internal Server Error"));
+        // Sentry may be back later
+        assert!(!error.is::<PermanentError>());
     }
 }
@@ -0,0 +1,285 @@
+use crate::channel::support::EnvReader;
+use crate::channel::Channel;
+use crate::csp::condition::host;
+use crate::csp::received_report::ReceivedReport;
+use anyhow::{Context, Error};
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Schema migrations, applied in order. The index of the last applied
+/// migration is stored in the `user_version` pragma.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE reports (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        received_at TEXT NOT NULL,
+        project TEXT,
+        user_agent TEXT,
+        filtered_by TEXT,
+        document_uri TEXT NOT NULL,
+        document_host TEXT,
+        blocked_uri TEXT NOT NULL,
+        blocked_host TEXT,
+        violated_directive TEXT NOT NULL,
+        effective_directive TEXT,
+        disposition TEXT,
+        original_policy TEXT NOT NULL,
+        referrer TEXT NOT NULL,
+        script_sample TEXT,
+        source_file TEXT,
+        line_number INTEGER,
+        column_number INTEGER,
+        status_code TEXT,
+        report TEXT NOT NULL
+    );
+
+    CREATE INDEX reports_document_host ON reports (document_host, received_at);
+    CREATE INDEX reports_violated_directive ON reports (violated_directive, received_at);
+    CREATE INDEX reports_effective_directive ON reports (effective_directive, received_at);
+    CREATE INDEX reports_blocked_uri ON reports (blocked_uri, received_at);
+    CREATE INDEX reports_project ON reports (project, received_at);
+"#,
+    r#"
+    ALTER TABLE reports ADD COLUMN fingerprint TEXT;
+    ALTER TABLE reports ADD COLUMN duplicates INTEGER NOT NULL DEFAULT 0;
+
+    CREATE INDEX reports_fingerprint ON reports (fingerprint, received_at);
+"#,
+];
+
+/// A connection, opened on first use
+type SharedConnection = Arc<Mutex<Option<Connection>>>;
+
+/// The connections by database path. The global and project channels
+/// storing in the same database share one connection, so the migrations
+/// are only applied once and the writes are serialized.
+static CONNECTIONS: OnceLock<Mutex<HashMap<String, SharedConnection>>> = OnceLock::new();
+
+/// SQLite storage channel
+#[derive(Clone)]
+pub struct Sqlite {
+    pub path: String,
+
+    /// Also store the reports dropped by the filters
+    pub store_filtered: bool,
+
+    connection: SharedConnection,
+}
+
+impl Sqlite {
+    pub fn new(path: String) -> Self {
+        let connection = CONNECTIONS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(path.clone())
+            .or_default()
+            .clone();
+
+        Sqlite {
+            path,
+            store_filtered: false,
+            connection,
+        }
+    }
+
+    /// Open the database, and apply the pending migrations
+    fn open(&self) -> Result<Connection, Error> {
+        let mut connection = Connection::open(&self.path)
+            .with_context(|| format!("Unable to open SQLite database {}", self.path))?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+
+        migrate(&mut connection)?;
+
+        Ok(connection)
+    }
+
+    /// Run a function with the shared connection, opening the database if
+    /// it is not open yet
+    fn with_connection<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Connection) -> Result<T, Error>,
+    {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.open()?);
+        }
+
+        f(connection.as_ref().expect("The connection is open"))
+    }
+}
+
+/// Apply the migrations which were not applied yet to the database
+pub fn migrate(connection: &mut Connection) -> Result<(), Error> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction
+            .execute_batch(migration)
+            .with_context(|| format!("Unable to apply migration {}", index + 1))?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Insert a report in the database, and return its id
+pub fn insert(connection: &Connection, report: &ReceivedReport) -> Result<i64, Error> {
+    let csp_report = &report.csp_report;
+
+    connection.execute(
+        "INSERT INTO reports (
+            received_at, project, user_agent, filtered_by,
+            document_uri, document_host, blocked_uri, blocked_host,
+            violated_directive, effective_directive, disposition,
+            original_policy, referrer, script_sample, source_file,
+            line_number, column_number, status_code, report,
+            fingerprint, duplicates
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)",
+        params![
+            report.received_at.to_rfc3339(),
+            report.project,
+            report.user_agent,
+            report.filtered_by,
+            csp_report.document_uri,
+            host(&csp_report.document_uri),
+            csp_report.blocked_uri,
+            host(&csp_report.blocked_uri),
+            csp_report.violated_directive,
+            csp_report.effective_directive,
+            csp_report.disposition,
+            csp_report.original_policy,
+            csp_report.referrer,
+            csp_report.script_sample,
+            csp_report.source_file,
+            csp_report.line_number,
+            csp_report.column_number,
+            csp_report.status_code,
+            serde_json::to_string(csp_report)?,
+            report.fingerprint,
+            report.duplicates,
+        ],
+    )?;
+
+    Ok(connection.last_insert_rowid())
+}
+
+#[async_trait]
+impl Channel for Sqlite {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let path = env
+            .optional("MORBO_SQLITE_PATH")
+            .unwrap_or_else(|| String::from("morbo.sqlite"));
+        let store_filtered = env
+            .parse_optional("MORBO_SQLITE_STORE_FILTERED")
+            .unwrap_or(false);
+
+        env.finish()?;
+
+        Ok(Sqlite {
+            store_filtered,
+            ..Sqlite::new(path)
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn accepts_filtered(&self) -> bool {
+        self.store_filtered
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let sqlite = self.clone();
+
+        tokio::task::spawn_blocking(move || sqlite.with_connection(|_| Ok(()))).await?
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let sqlite = self.clone();
+        let report = report.clone();
+
+        tokio::task::spawn_blocking(move || {
+            sqlite.with_connection(|connection| insert(connection, &report).map(|_| ()))
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    #[test]
+    fn it_migrates_and_stores_reports() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        // Migrating twice is a no-op
+        migrate(&mut connection).unwrap();
+
+        let csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://checkout.example.org/pay",
+            "default-src 'self'",
+            "",
+            "script-src",
+        );
+        let mut report = ReceivedReport::new(csp_report);
+        report.project = Some(String::from("checkout"));
+        report.duplicates = 3;
+
+        insert(&connection, &report).unwrap();
+
+        let (document_host, blocked_host, project, duplicates): (String, String, String, u64) =
+            connection
+                .query_row(
+                    "SELECT document_host, blocked_host, project, duplicates FROM reports",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .unwrap();
+
+        assert_eq!("checkout.example.org", document_host);
+        assert_eq!("evil.example.com", blocked_host);
+        assert_eq!("checkout", project);
+        assert_eq!(3, duplicates);
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_connection_of_a_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("morbo.sqlite").display().to_string();
+
+        let global = Sqlite::new(path.clone());
+        let project = Sqlite::new(path);
+        assert!(Arc::ptr_eq(&global.connection, &project.connection));
+
+        let report = ReceivedReport::new(CspReportContent::default(
+            "inline",
+            "https://example.org/",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+        let (first, second) =
+            tokio::join!(global.send_report(&report), project.send_report(&report));
+        first.unwrap();
+        second.unwrap();
+
+        let count: u32 = global
+            .with_connection(|connection| {
+                Ok(connection.query_row("SELECT COUNT(*) FROM reports", [], |row| row.get(0))?)
+            })
+            .unwrap();
+        assert_eq!(2, count);
+    }
+}
@@ -0,0 +1,111 @@
+//! Helpers shared by the channels. Each channel only uses some of them,
+//! and none is used when no channel is enabled.
+#![allow(dead_code)]
+
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use tokio::net::TcpStream;
+
+/// Maximum time to connect to a server when checking a channel
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the environment variables of a channel, collecting every missing
+/// or invalid one instead of stopping at the first
+#[derive(Default)]
+pub struct EnvReader {
+    errors: Vec<String>,
+}
+
+impl EnvReader {
+    /// Read an optional variable, empty values being ignored
+    pub fn optional(&mut self, name: &str) -> Option<String> {
+        env::var(name).ok().filter(|value| !value.is_empty())
+    }
+
+    /// Read a required variable
+    pub fn required(&mut self, name: &str) -> String {
+        self.optional(name).unwrap_or_else(|| {
+            self.errors.push(format!("{} is missing", name));
+            String::new()
+        })
+    }
+
+    /// Read and parse an optional variable
+    pub fn parse_optional<T>(&mut self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.optional(name)?.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("{} is invalid: {}", name, e));
+                None
+            }
+        }
+    }
+
+    /// Read and parse a required variable
+    pub fn parse<T>(&mut self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if self.optional(name).is_none() {
+            self.errors.push(format!("{} is missing", name));
+            return None;
+        }
+
+        self.parse_optional(name)
+    }
+
+    /// Record an error which is not about a single variable
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.errors.push(message.into());
+    }
+
+    /// Fail with every error found
+    pub fn finish(self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(self.errors.join(", ")))
+        }
+    }
+}
+
+/// Check that a TCP connection can be opened to a server
+pub async fn check_connection(host: &str, port: u16) -> Result<(), Error> {
+    tokio::time::timeout(CHECK_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| anyhow!("Timeout connecting to {}:{}", host, port))?
+        .with_context(|| format!("Unable to connect to {}:{}", host, port))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_every_invalid_variable() {
+        env::set_var("MORBO_TEST_ENV_READER_PORT", "smtp");
+        env::set_var("MORBO_TEST_ENV_READER_EMPTY", "");
+
+        let mut env = EnvReader::default();
+        env.required("MORBO_TEST_ENV_READER_EMPTY");
+        env.parse::<u16>("MORBO_TEST_ENV_READER_PORT");
+        assert!(env.optional("MORBO_TEST_ENV_READER_MISSING").is_none());
+
+        let error = env.finish().unwrap_err().to_string();
+        assert_eq!(
+            "MORBO_TEST_ENV_READER_EMPTY is missing, MORBO_TEST_ENV_READER_PORT is invalid: invalid digit found in string",
+            error
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Error};
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+
+use crate::channel::support::{check_connection, EnvReader};
+use crate::channel::Channel;
+use crate::csp::received_report::ReceivedReport;
+
+/// Private enterprise number reserved for documentation (RFC 5612), used in
+/// the structured data IDs unless another one is configured
+const DEFAULT_ENTERPRISE_ID: u32 = 32473;
+
+/// The facility of the messages, like `user` or `local0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facility(pub u8);
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const FACILITIES: &[&str] = &[
+            "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+            "authpriv", "ftp", "ntp", "audit", "alert", "clock",
+        ];
+
+        let s = s.to_lowercase();
+        if let Some(code) = FACILITIES.iter().position(|name| *name == s) {
+            return Ok(Facility(code as u8));
+        }
+        match s.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+            Some(n) if n <= 7 => Ok(Facility(16 + n)),
+            _ => Err(String::from(
+                "expected a facility like user, daemon, auth or local0 to local7",
+            )),
+        }
+    }
+}
+
+/// The severity of the messages, like `warning` or `notice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Severity(pub u8);
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "emerg" | "emergency" => Ok(Severity(0)),
+            "alert" => Ok(Severity(1)),
+            "crit" | "critical" => Ok(Severity(2)),
+            "err" | "error" => Ok(Severity(3)),
+            "warning" | "warn" => Ok(Severity(4)),
+            "notice" => Ok(Severity(5)),
+            "info" => Ok(Severity(6)),
+            "debug" => Ok(Severity(7)),
+            _ => Err(String::from(
+                "expected emerg, alert, crit, err, warning, notice, info or debug",
+            )),
+        }
+    }
+}
+
+/// Where the messages are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A datagram per message
+    Udp(String),
+    /// A stream of messages, each prefixed with its length (RFC 6587)
+    Tcp(String),
+    /// A datagram per message, to a local socket like `/dev/log`
+    Unix(PathBuf),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || String::from("expected udp://host:port, tcp://host:port or unix:///path");
+
+        match s.split_once("://") {
+            Some(("udp", address)) if !address.is_empty() => Ok(Transport::Udp(address.into())),
+            Some(("tcp", address)) if !address.is_empty() => Ok(Transport::Tcp(address.into())),
+            Some(("unix", path)) if path.starts_with('/') => Ok(Transport::Unix(path.into())),
+            _ => Err(error()),
+        }
+    }
+}
+
+/// Syslog channel
+#[derive(Clone)]
+pub struct Syslog {
+    pub transport: Transport,
+    pub facility: Facility,
+    pub severity: Severity,
+    pub hostname: Option<String>,
+    pub app_name: String,
+
+    /// Private enterprise number of the structured data IDs
+    pub enterprise_id: u32,
+    pub timeout: Duration,
+
+    /// The TCP connection, kept open between reports
+    connection: Arc<Mutex<Option<TcpStream>>>,
+}
+
+/// A header field: printable ASCII only, cut to its maximum length, or the
+/// nil value when empty
+fn header_field(value: &str, max: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+
+    if value.is_empty() {
+        String::from("-")
+    } else {
+        value
+    }
+}
+
+/// Escape a structured data parameter value
+fn param_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+/// The first socket address of a host
+async fn resolve(address: &str) -> Result<SocketAddr, Error> {
+    lookup_host(address)
+        .await
+        .with_context(|| format!("Unable to resolve {}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("Unable to resolve {}", address))
+}
+
+/// The name of the machine, from the kernel or `/etc/hostname`
+fn system_hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|hostname| hostname.trim().to_string())
+        .find(|hostname| !hostname.is_empty())
+}
+
+impl Syslog {
+    /// Format a report as an RFC 5424 message. The CSP fields are in the
+    /// `csp@<enterprise id>` structured data element, and the context of the
+    /// report in the `morbo@<enterprise id>` one.
+    pub fn format(&self, report: &ReceivedReport) -> String {
+        let csp_report = &report.csp_report;
+        let directive = csp_report
+            .effective_directive
+            .as_deref()
+            .unwrap_or(&csp_report.violated_directive);
+
+        let mut message = format!(
+            "<{}>1 {} {} {} {} csp-violation ",
+            u16::from(self.facility.0) * 8 + u16::from(self.severity.0),
+            report
+                .received_at
+                .to_rfc3339_opts(SecondsFormat::Micros, true),
+            header_field(self.hostname.as_deref().unwrap_or_default(), 255),
+            header_field(&self.app_name, 48),
+            std::process::id(),
+        );
+
+        let mut element = |id: &str, params: &[(&str, Option<&str>)]| {
+            let _ = write!(message, "[{}@{}", id, self.enterprise_id);
+            for (name, value) in params {
+                if let Some(value) = value.filter(|value| !value.is_empty()) {
+                    let _ = write!(message, " {}=\"{}\"", name, param_value(value));
+                }
+            }
+            message.push(']');
+        };
+
+        let line_number = csp_report.line_number.map(|n| n.to_string());
+        let column_number = csp_report.column_number.map(|n| n.to_string());
+        element(
+            "csp",
+            &[
+                ("document-uri", Some(&csp_report.document_uri)),
+                ("blocked-uri", Some(&csp_report.blocked_uri)),
+                ("violated-directive", Some(&csp_report.violated_directive)),
+                (
+                    "effective-directive",
+                    csp_report.effective_directive.as_deref(),
+                ),
+                ("disposition", csp_report.disposition.as_deref()),
+                ("source-file", csp_report.source_file.as_deref()),
+                ("line-number", line_number.as_deref()),
+                ("column-number", column_number.as_deref()),
+                ("status-code", csp_report.status_code.as_deref()),
+                ("referrer", Some(&csp_report.referrer)),
+                ("script-sample", csp_report.script_sample.as_deref()),
+            ],
+        );
+
+        let duplicates = report.duplicates.to_string();
+        element(
+            "morbo",
+            &[
+                ("project", report.project.as_deref()),
+                ("fingerprint", report.fingerprint.as_deref()),
+                ("duplicates", Some(&duplicates)),
+                ("user-agent", report.user_agent.as_deref()),
+            ],
+        );
+
+        // The message is sent without BOM, as most receivers display it
+        let _ = write!(
+            message,
+            " {} blocked {} on {}",
+            directive, csp_report.blocked_uri, csp_report.document_uri
+        );
+
+        message
+    }
+
+    /// Send a message over the TCP connection, opening it if needed
+    async fn send_tcp(&self, address: &str, message: &str) -> Result<(), Error> {
+        let mut connection = self.connection.lock().await;
+
+        let stream = match &mut *connection {
+            Some(stream) => stream,
+            None => {
+                let stream = tokio::time::timeout(self.timeout, TcpStream::connect(address))
+                    .await
+                    .map_err(|_| anyhow!("Timeout connecting to {}", address))?
+                    .with_context(|| format!("Unable to connect to {}", address))?;
+                connection.insert(stream)
+            }
+        };
+
+        // Octet counting framing, as messages may contain new lines
+        let frame = format!("{} {}", message.len(), message);
+        let written = tokio::time::timeout(self.timeout, stream.write_all(frame.as_bytes()))
+            .await
+            .map_err(|_| anyhow!("Timeout sending the message to {}", address))
+            .and_then(|written| {
+                written.with_context(|| format!("Unable to send the message to {}", address))
+            });
+
+        // The connection is opened again by the next message
+        if written.is_err() {
+            *connection = None;
+        }
+
+        written
+    }
+
+    async fn send_udp(address: &str, message: &str) -> Result<(), Error> {
+        let target = resolve(address).await?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .send_to(message.as_bytes(), target)
+            .await
+            .with_context(|| format!("Unable to send the message to {}", address))?;
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    async fn send_unix(path: &std::path::Path, message: &str) -> Result<(), Error> {
+        let socket = tokio::net::UnixDatagram::unbound()?;
+        socket
+            .send_to(message.as_bytes(), path)
+            .await
+            .with_context(|| format!("Unable to send the message to {}", path.display()))?;
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn send_unix(_path: &std::path::Path, _message: &str) -> Result<(), Error> {
+        bail!("Unix sockets are not supported on this platform")
+    }
+}
+
+#[async_trait]
+impl Channel for Syslog {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let transport = env
+            .parse_optional("MORBO_SYSLOG_ADDRESS")
+            .unwrap_or_else(|| Transport::Unix(PathBuf::from("/dev/log")));
+        let facility = env
+            .parse_optional("MORBO_SYSLOG_FACILITY")
+            .unwrap_or(Facility(1));
+        let severity = env
+            .parse_optional("MORBO_SYSLOG_SEVERITY")
+            .unwrap_or(Severity(4));
+        let hostname = env
+            .optional("MORBO_SYSLOG_HOSTNAME")
+            .or_else(system_hostname);
+        let app_name = env
+            .optional("MORBO_SYSLOG_APP_NAME")
+            .unwrap_or_else(|| String::from("morbo"));
+        let enterprise_id = env
+            .parse_optional("MORBO_SYSLOG_ENTERPRISE_ID")
+            .unwrap_or(DEFAULT_ENTERPRISE_ID);
+        let timeout = env.parse_optional("MORBO_SYSLOG_TIMEOUT").unwrap_or(10);
+
+        env.finish()?;
+
+        Ok(Syslog {
+            transport,
+            facility,
+            severity,
+            hostname,
+            app_name,
+            enterprise_id,
+            timeout: Duration::from_secs(timeout),
+            connection: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        match &self.transport {
+            Transport::Udp(address) => {
+                resolve(address).await?;
+            }
+            Transport::Tcp(address) => {
+                let (host, port) = address
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                    .ok_or_else(|| anyhow!("Invalid address {}", address))?;
+                check_connection(host.trim_matches(['[', ']']), port).await?;
+            }
+            Transport::Unix(path) => {
+                if !path.exists() {
+                    bail!("{} does not exist", path.display());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let message = self.format(report);
+
+        match &self.transport {
+            Transport::Udp(address) => Self::send_udp(address, &message).await,
+            Transport::Tcp(address) => self.send_tcp(address, &message).await,
+            Transport::Unix(path) => Self::send_unix(path, &message).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn syslog(transport: Transport) -> Syslog {
+        Syslog {
+            transport,
+            facility: Facility(16),
+            severity: Severity(4),
+            hostname: Some(String::from("csp.example.org")),
+            app_name: String::from("morbo"),
+            enterprise_id: DEFAULT_ENTERPRISE_ID,
+            timeout: Duration::from_secs(5),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn report() -> ReceivedReport {
+        let mut csp_report = CspReportContent::default(
+            "https://evil.example.com/x.js",
+            "https://shop.example.org/cart",
+            "default-src 'self'",
+            "",
+            "script-src",
+        );
+        csp_report.disposition = Some(String::from("enforce"));
+        csp_report.script_sample = Some(String::from(r#"alert("]\")"#));
+        let mut report = ReceivedReport::new(csp_report);
+        report.received_at = "2024-05-01T12:30:00Z".parse().unwrap();
+        report.project = Some(String::from("shop"));
+        report
+    }
+
+    #[test]
+    fn it_parses_the_settings() {
+        assert_eq!(Ok(Facility(1)), "user".parse());
+        assert_eq!(Ok(Facility(23)), "LOCAL7".parse());
+        assert!("local8".parse::<Facility>().is_err());
+        assert_eq!(Ok(Severity(5)), "notice".parse());
+        assert!("loud".parse::<Severity>().is_err());
+
+        assert_eq!(
+            Ok(Transport::Tcp(String::from("siem.example.org:6514"))),
+            "tcp://siem.example.org:6514".parse()
+        );
+        assert_eq!(
+            Ok(Transport::Unix(PathBuf::from("/dev/log"))),
+            "unix:///dev/log".parse()
+        );
+        assert!("syslog.example.org:514".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn it_formats_rfc_5424_messages() {
+        let message = syslog(Transport::Udp(String::from("localhost:514"))).format(&report());
+
+        assert_eq!(
+            format!(
+                concat!(
+                    "<132>1 2024-05-01T12:30:00.000000Z csp.example.org morbo {} csp-violation ",
+                    "[csp@32473 document-uri=\"https://shop.example.org/cart\" ",
+                    "blocked-uri=\"https://evil.example.com/x.js\" violated-directive=\"script-src\" ",
+                    "disposition=\"enforce\" script-sample=\"alert(\\\"\\]\\\\\\\")\"]",
+                    "[morbo@32473 project=\"shop\" duplicates=\"0\"] ",
+                    "script-src blocked https://evil.example.com/x.js on https://shop.example.org/cart"
+                ),
+                std::process::id()
+            ),
+            message
+        );
+        assert_eq!("-", header_field(" ", 48));
+        assert_eq!("mor", header_field("mor bo", 3));
+    }
+
+    #[tokio::test]
+    async fn it_sends_datagrams_over_udp() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let syslog = syslog(Transport::Udp(listener.local_addr().unwrap().to_string()));
+
+        syslog.send_report(&report()).await.unwrap();
+
+        let mut buffer = [0; 4096];
+        let read = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(syslog.format(&report()).as_bytes(), &buffer[..read]);
+    }
+
+    #[tokio::test]
+    async fn it_frames_messages_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let syslog = syslog(Transport::Tcp(listener.local_addr().unwrap().to_string()));
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).await.unwrap();
+            received
+        });
+
+        // Both messages go through the same connection
+        syslog.send_report(&report()).await.unwrap();
+        syslog.send_report(&report()).await.unwrap();
+        *syslog.connection.lock().await = None;
+
+        let message = syslog.format(&report());
+        assert_eq!(
+            format!("{} {}", message.len(), message).repeat(2),
+            server.await.unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_sends_datagrams_to_unix_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let listener = tokio::net::UnixDatagram::bind(&path).unwrap();
+        let syslog = syslog(Transport::Unix(path));
+
+        syslog.check().await.unwrap();
+        syslog.send_report(&report()).await.unwrap();
+
+        let mut buffer = [0; 4096];
+        let read = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(syslog.format(&report()).as_bytes(), &buffer[..read]);
+    }
+}
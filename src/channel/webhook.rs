@@ -0,0 +1,399 @@
+use crate::channel::http::check_status;
+use crate::channel::support::{check_connection, EnvReader};
+use crate::channel::{Channel, PermanentError};
+use crate::csp::received_report::ReceivedReport;
+use anyhow::{anyhow, Context, Error};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+use url::Url;
+
+/// Prefix of the variables adding a header to the requests, like
+/// `MORBO_WEBHOOK_HEADER_AUTHORIZATION`
+const HEADER_PREFIX: &str = "MORBO_WEBHOOK_HEADER_";
+
+/// Header holding the time of the request, in seconds since the epoch
+pub const TIMESTAMP_HEADER: &str = "x-morbo-timestamp";
+
+/// Header holding the HMAC-SHA256 of the timestamp and the body
+pub const SIGNATURE_HEADER: &str = "x-morbo-signature";
+
+/// Header identifying a report, the same across retries
+pub const DELIVERY_HEADER: &str = "x-morbo-delivery";
+
+/// Maximum number of reports whose partial delivery is remembered
+const MAX_PARTIAL_DELIVERIES: usize = 1000;
+
+/// The URLs which already received the reports that failed on other URLs,
+/// so retries only post to the failed ones
+#[derive(Debug, Default)]
+struct PartialDeliveries {
+    /// The URLs by delivery, with the order of insertion
+    delivered: HashMap<String, (u64, Vec<Url>)>,
+    next: u64,
+}
+
+impl PartialDeliveries {
+    /// Take the URLs which already received a report
+    fn take(&mut self, delivery: &str) -> Vec<Url> {
+        self.delivered
+            .remove(delivery)
+            .map(|(_, urls)| urls)
+            .unwrap_or_default()
+    }
+
+    /// Remember the URLs which received a report, forgetting the oldest
+    /// report when there are too many
+    fn insert(&mut self, delivery: String, urls: Vec<Url>) {
+        if urls.is_empty() {
+            return;
+        }
+
+        if self.delivered.len() >= MAX_PARTIAL_DELIVERIES {
+            let oldest = self
+                .delivered
+                .iter()
+                .min_by_key(|(_, (order, _))| *order)
+                .map(|(delivery, _)| delivery.clone());
+            if let Some(oldest) = oldest {
+                self.delivered.remove(&oldest);
+            }
+        }
+
+        self.delivered.insert(delivery, (self.next, urls));
+        self.next += 1;
+    }
+}
+
+/// Webhook channel
+#[derive(Clone)]
+pub struct Webhook {
+    pub urls: Vec<Url>,
+
+    /// Secret of the signature, requests are not signed without it
+    pub secret: Option<String>,
+
+    pub headers: HeaderMap,
+
+    client: Client,
+    partial_deliveries: Arc<Mutex<PartialDeliveries>>,
+}
+
+/// Parse the URL of a receiver
+pub fn parse_url(url: &str) -> Result<Url, Error> {
+    match Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(url),
+        _ => Err(anyhow!("invalid URL {:?}", url)),
+    }
+}
+
+/// The signature of a request: the HMAC-SHA256 of `{timestamp}.{body}`
+pub fn signature(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Read the custom headers from the `MORBO_WEBHOOK_HEADER_*` variables,
+/// underscores in the name of the variable becoming dashes
+fn headers_from_env(env_reader: &mut EnvReader) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in env::vars() {
+        let header = match name.strip_prefix(HEADER_PREFIX) {
+            Some(header) if !value.is_empty() => header.replace('_', "-").to_lowercase(),
+            _ => continue,
+        };
+
+        match (
+            HeaderName::from_bytes(header.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(header), Ok(value)) => {
+                headers.insert(header, value);
+            }
+            _ => env_reader.error(format!("{} is not a valid header", name)),
+        }
+    }
+
+    headers
+}
+
+impl Webhook {
+    /// Send the body to an URL
+    async fn post(&self, url: &Url, body: &[u8], delivery: &str) -> Result<(), Error> {
+        let mut request = self
+            .client
+            .post(url.clone())
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery)
+            .body(body.to_vec());
+
+        if let Some(secret) = &self.secret {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            request = request
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(SIGNATURE_HEADER, signature(secret, timestamp, body));
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Unable to send the report to {}", url))?;
+
+        check_status(url.as_str(), response.status())
+    }
+}
+
+#[async_trait]
+impl Channel for Webhook {
+    fn load_from_env() -> Result<Self, Error> {
+        let mut env = EnvReader::default();
+
+        let urls = env
+            .required("MORBO_WEBHOOK_URLS")
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .filter_map(|url| match parse_url(url) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    env.error(format!("MORBO_WEBHOOK_URLS has an {}", e));
+                    None
+                }
+            })
+            .collect();
+        let secret = env.optional("MORBO_WEBHOOK_SECRET");
+        let timeout = env.parse_optional("MORBO_WEBHOOK_TIMEOUT").unwrap_or(10);
+        let headers = headers_from_env(&mut env);
+
+        env.finish()?;
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .context("Unable to create the HTTP client")?;
+
+        Ok(Webhook {
+            urls,
+            secret,
+            headers,
+            client,
+            partial_deliveries: Arc::default(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        for url in &self.urls {
+            let host = url.host_str().unwrap_or_default();
+            let port = url.port_or_known_default().unwrap_or(80);
+            check_connection(host, port).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let body = serde_json::to_vec(report)?;
+        // Retries send the same body, so receivers can skip the reports
+        // they already got
+        let delivery = hex::encode(&Sha256::digest(&body)[..16]);
+
+        // A retried report is only posted to the URLs which failed
+        let mut delivered = self.partial_deliveries.lock().unwrap().take(&delivery);
+        let mut errors = vec![];
+        for url in &self.urls {
+            if delivered.contains(url) {
+                debug!("Report already sent to {}", url);
+                continue;
+            }
+
+            match self.post(url, &body, &delivery).await {
+                Ok(()) => {
+                    debug!("Report sent to {}", url);
+                    delivered.push(url.clone());
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            self.partial_deliveries
+                .lock()
+                .unwrap()
+                .insert(delivery, delivered);
+        }
+
+        // Permanent errors are only permanent when no URL can be retried
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => {
+                let permanent = errors.iter().all(|e| e.is::<PermanentError>());
+                let error = anyhow!(errors
+                    .iter()
+                    .map(|e| format!("{:#}", e))
+                    .collect::<Vec<_>>()
+                    .join(", "));
+
+                if permanent {
+                    Err(PermanentError(error).into())
+                } else {
+                    Err(error)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answer the requests with the given statuses, the last one being
+    /// repeated, and return the requests received
+    async fn serve(statuses: Vec<&'static str>) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/csp", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = [0; 4096];
+                // Read until the end of the JSON body
+                while !request.ends_with(b"}") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let index = {
+                    let mut received = received.lock().unwrap();
+                    received.push(String::from_utf8(request).unwrap());
+                    received.len() - 1
+                };
+                let status = statuses[index.min(statuses.len() - 1)];
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn it_signs_the_timestamp_and_the_body() {
+        assert_eq!(
+            "sha256=49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686",
+            signature("secret", 1700000000, br#"{"a":1}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_posts_signed_reports() {
+        let (url, requests) = serve(vec!["204 No Content"]).await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-team", HeaderValue::from_static("security"));
+        let webhook = Webhook {
+            urls: vec![url],
+            secret: Some(String::from("secret")),
+            headers,
+            client: Client::new(),
+            partial_deliveries: Arc::default(),
+        };
+        let report = ReceivedReport::new(CspReportContent::default(
+            "inline",
+            "https://shop.example.org/",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+        webhook.send_report(&report).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        let (head, body) = requests[0].split_once("\r\n\r\n").unwrap();
+        let timestamp: u64 = head
+            .lines()
+            .find_map(|line| line.strip_prefix("x-morbo-timestamp: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert!(head.starts_with("POST /csp HTTP/1.1"));
+        assert!(head.contains("x-team: security"));
+        assert!(head.contains(&format!(
+            "x-morbo-signature: {}",
+            signature("secret", timestamp, body.as_bytes())
+        )));
+        assert_eq!(
+            "inline",
+            serde_json::from_str::<ReceivedReport>(body)
+                .unwrap()
+                .csp_report
+                .blocked_uri
+        );
+    }
+
+    #[tokio::test]
+    async fn it_only_retries_the_failed_urls() {
+        let (ok_url, ok_requests) = serve(vec!["204 No Content"]).await;
+        let (failing_url, failing_requests) =
+            serve(vec!["503 Service Unavailable", "204 No Content"]).await;
+
+        let webhook = Webhook {
+            urls: vec![ok_url, failing_url],
+            secret: None,
+            headers: HeaderMap::new(),
+            client: Client::new(),
+            partial_deliveries: Arc::default(),
+        };
+        let report = ReceivedReport::new(CspReportContent::default(
+            "inline",
+            "https://shop.example.org/",
+            "default-src 'self'",
+            "",
+            "script-src",
+        ));
+
+        assert!(webhook.send_report(&report).await.is_err());
+        webhook.send_report(&report).await.unwrap();
+
+        assert_eq!(1, ok_requests.lock().unwrap().len());
+        assert_eq!(2, failing_requests.lock().unwrap().len());
+        assert!(webhook
+            .partial_deliveries
+            .lock()
+            .unwrap()
+            .delivered
+            .is_empty());
+    }
+}
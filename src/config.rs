@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use serde::de::DeserializeOwned;
+
+/// The format of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Guess the format of a file from its extension, defaulting to TOML
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    /// Deserialize the content of a file in this format
+    pub fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T, Error> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+        })
+    }
+}
+
+/// Read a TOML or YAML file, and hand its content and format to a parser
+pub fn load<T, F>(path: &Path, parse: F) -> Result<T, Error>
+where
+    F: FnOnce(&str, ConfigFormat) -> Result<T, Error>,
+{
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+
+    parse(&content, ConfigFormat::from_path(path))
+        .with_context(|| format!("Invalid file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_guesses_the_format_from_the_extension() {
+        assert_eq!(
+            ConfigFormat::Yaml,
+            ConfigFormat::from_path(Path::new("rules.yml"))
+        );
+        assert_eq!(
+            ConfigFormat::Yaml,
+            ConfigFormat::from_path(Path::new("rules.yaml"))
+        );
+        assert_eq!(
+            ConfigFormat::Toml,
+            ConfigFormat::from_path(Path::new("rules.toml"))
+        );
+    }
+}
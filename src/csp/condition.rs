@@ -0,0 +1,412 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use anyhow::{bail, Context, Error};
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use url::Url;
+
+use crate::csp::csp_report_content::CspReportContent;
+use crate::csp::matcher::{MatchMode, Matcher};
+
+/// A report field a condition can be tested against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    BlockedUri,
+    /// The host of the blocked URI
+    BlockedHost,
+    ColumnNumber,
+    Disposition,
+    DocumentUri,
+    /// The host of the document URI
+    DocumentHost,
+    EffectiveDirective,
+    LineNumber,
+    OriginalPolicy,
+    Referrer,
+    ScriptSample,
+    SourceFile,
+    StatusCode,
+    ViolatedDirective,
+}
+
+impl Field {
+    /// The name of the field, as written in rules files
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Field::BlockedUri => "blocked_uri",
+            Field::BlockedHost => "blocked_host",
+            Field::ColumnNumber => "column_number",
+            Field::Disposition => "disposition",
+            Field::DocumentUri => "document_uri",
+            Field::DocumentHost => "document_host",
+            Field::EffectiveDirective => "effective_directive",
+            Field::LineNumber => "line_number",
+            Field::OriginalPolicy => "original_policy",
+            Field::Referrer => "referrer",
+            Field::ScriptSample => "script_sample",
+            Field::SourceFile => "source_file",
+            Field::StatusCode => "status_code",
+            Field::ViolatedDirective => "violated_directive",
+        }
+    }
+
+    /// The value of the field in a report, if the report has one
+    pub fn value(self, report: &CspReportContent) -> Option<Cow<'_, str>> {
+        match self {
+            Field::BlockedUri => Some(Cow::from(&report.blocked_uri)),
+            Field::BlockedHost => host(&report.blocked_uri).map(Cow::from),
+            Field::ColumnNumber => report.column_number.map(|n| Cow::from(n.to_string())),
+            Field::Disposition => report.disposition.as_deref().map(Cow::from),
+            Field::DocumentUri => Some(Cow::from(&report.document_uri)),
+            Field::DocumentHost => host(&report.document_uri).map(Cow::from),
+            Field::EffectiveDirective => report.effective_directive.as_deref().map(Cow::from),
+            Field::LineNumber => report.line_number.map(|n| Cow::from(n.to_string())),
+            Field::OriginalPolicy => Some(Cow::from(&report.original_policy)),
+            Field::Referrer => Some(Cow::from(&report.referrer)),
+            Field::ScriptSample => report.script_sample.as_deref().map(Cow::from),
+            Field::SourceFile => report.source_file.as_deref().map(Cow::from),
+            Field::StatusCode => report.status_code.as_deref().map(Cow::from),
+            Field::ViolatedDirective => Some(Cow::from(&report.violated_directive)),
+        }
+    }
+}
+
+/// The host of an URI, if it has one
+pub fn host(uri: &str) -> Option<String> {
+    Url::parse(uri)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+}
+
+/// A condition on the fields of a report, combined with boolean logic
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// Every condition is true
+    All(Vec<Condition>),
+    /// At least one condition is true
+    Any(Vec<Condition>),
+    /// The condition is false
+    Not(Box<Condition>),
+    /// The field exists and matches the pattern
+    Match { field: Field, matcher: Matcher },
+}
+
+impl Condition {
+    /// Test the condition against a report
+    pub fn evaluate(&self, report: &CspReportContent) -> bool {
+        match self {
+            Condition::All(conditions) => conditions.iter().all(|c| c.evaluate(report)),
+            Condition::Any(conditions) => conditions.iter().any(|c| c.evaluate(report)),
+            Condition::Not(condition) => !condition.evaluate(report),
+            Condition::Match { field, matcher } => field
+                .value(report)
+                .is_some_and(|value| matcher.is_match(&value)),
+        }
+    }
+}
+
+/// A condition, as written in a rules file
+#[derive(Debug)]
+pub enum ConditionConfig {
+    All {
+        all: Vec<ConditionConfig>,
+    },
+    Any {
+        any: Vec<ConditionConfig>,
+    },
+    Not {
+        not: Box<ConditionConfig>,
+    },
+    Match {
+        field: Field,
+        pattern: String,
+        mode: MatchMode,
+    },
+}
+
+/// The keys a condition can have
+const CONDITION_KEYS: &[&str] = &["all", "any", "not", "field", "pattern", "mode"];
+
+impl<'de> Deserialize<'de> for ConditionConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(ConditionVisitor)
+    }
+}
+
+/// Reads a condition key by key, so a misspelled key is reported by name
+/// instead of as a shape matching no condition
+struct ConditionVisitor;
+
+impl<'de> Visitor<'de> for ConditionVisitor {
+    type Value = ConditionConfig;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a condition with all, any, not or field and pattern")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut combined: Option<(String, ConditionConfig)> = None;
+        let mut field = None;
+        let mut pattern = None;
+        let mut mode = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "all" | "any" | "not" => {
+                    if let Some((other, _)) = &combined {
+                        return Err(de::Error::custom(format!(
+                            "`{}` and `{}` cannot be combined, nest them instead",
+                            other, key
+                        )));
+                    }
+                    let condition = match key.as_str() {
+                        "all" => ConditionConfig::All {
+                            all: map.next_value()?,
+                        },
+                        "any" => ConditionConfig::Any {
+                            any: map.next_value()?,
+                        },
+                        _ => ConditionConfig::Not {
+                            not: map.next_value()?,
+                        },
+                    };
+                    combined = Some((key, condition));
+                }
+                "field" => {
+                    if field.is_some() {
+                        return Err(de::Error::duplicate_field("field"));
+                    }
+                    field = Some(map.next_value()?);
+                }
+                "pattern" => {
+                    if pattern.is_some() {
+                        return Err(de::Error::duplicate_field("pattern"));
+                    }
+                    pattern = Some(map.next_value()?);
+                }
+                "mode" => {
+                    if mode.is_some() {
+                        return Err(de::Error::duplicate_field("mode"));
+                    }
+                    mode = Some(map.next_value()?);
+                }
+                _ => return Err(de::Error::unknown_field(&key, CONDITION_KEYS)),
+            }
+        }
+
+        match (combined, field, pattern) {
+            (Some((_, condition)), None, None) if mode.is_none() => Ok(condition),
+            (Some((key, _)), _, _) => Err(de::Error::custom(format!(
+                "`{}` cannot be combined with field, pattern or mode",
+                key
+            ))),
+            (None, Some(field), Some(pattern)) => Ok(ConditionConfig::Match {
+                field,
+                pattern,
+                mode: mode.unwrap_or_default(),
+            }),
+            (None, Some(_), None) => Err(de::Error::missing_field("pattern")),
+            (None, None, Some(_)) => Err(de::Error::missing_field("field")),
+            (None, None, None) => Err(de::Error::custom(
+                "expected all, any, not or field and pattern",
+            )),
+        }
+    }
+}
+
+impl ConditionConfig {
+    /// Compile the condition and its patterns
+    pub fn compile(self) -> Result<Condition, Error> {
+        Ok(match self {
+            ConditionConfig::All { all } => Condition::All(compile_all("all", all)?),
+            ConditionConfig::Any { any } => Condition::Any(compile_all("any", any)?),
+            ConditionConfig::Not { not } => Condition::Not(Box::new(not.compile()?)),
+            ConditionConfig::Match {
+                field,
+                pattern,
+                mode,
+            } => Condition::Match {
+                field,
+                matcher: Matcher::new(&pattern, mode)
+                    .with_context(|| format!("Invalid pattern for field {:?}", field))?,
+            },
+        })
+    }
+}
+
+/// Compile the conditions of an `all` or `any` list, which would otherwise
+/// match every report or none when empty
+fn compile_all(key: &str, conditions: Vec<ConditionConfig>) -> Result<Vec<Condition>, Error> {
+    if conditions.is_empty() {
+        bail!("`{}` needs at least one condition", key);
+    }
+
+    conditions
+        .into_iter()
+        .map(ConditionConfig::compile)
+        .collect()
+}
+
+/// A named condition dropping the reports it matches
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+}
+
+/// A rule, as written in a rules file
+#[derive(Debug, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub condition: ConditionConfig,
+}
+
+impl RuleConfig {
+    pub fn compile(self) -> Result<Rule, Error> {
+        let name = self.name;
+        let condition = self
+            .condition
+            .compile()
+            .with_context(|| format!("Invalid rule {:?}", name))?;
+
+        Ok(Rule { name, condition })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> CspReportContent {
+        let mut report = CspReportContent::default(
+            "https://cdn.example.com/img/logo.png",
+            "https://staging.example.org/checkout",
+            "default-src 'self'",
+            "",
+            "img-src",
+        );
+        report.effective_directive = Some(String::from("img-src"));
+        report.disposition = Some(String::from("report"));
+        report
+    }
+
+    fn compile(rule: &str) -> Condition {
+        let rule: RuleConfig = toml::from_str(rule).unwrap();
+        rule.compile().unwrap().condition
+    }
+
+    #[test]
+    fn it_reads_field_values() {
+        let report = report();
+        assert_eq!(
+            Some("cdn.example.com"),
+            Field::BlockedHost.value(&report).as_deref()
+        );
+        assert_eq!(
+            Some("staging.example.org"),
+            Field::DocumentHost.value(&report).as_deref()
+        );
+        assert_eq!(None, Field::SourceFile.value(&report));
+    }
+
+    #[test]
+    fn it_evaluates_all_conditions() {
+        let condition = compile(
+            r#"
+            name = "staging cdn images"
+            all = [
+                { field = "blocked_uri", pattern = "https://cdn.example", mode = "prefix" },
+                { field = "effective_directive", pattern = "img-src", mode = "exact" },
+                { field = "document_host", pattern = "staging.*", mode = "glob" },
+            ]
+            "#,
+        );
+        assert!(condition.evaluate(&report()));
+
+        let mut report = report();
+        report.effective_directive = Some(String::from("script-src"));
+        assert!(!condition.evaluate(&report));
+    }
+
+    #[test]
+    fn it_evaluates_any_and_not_conditions() {
+        let condition = compile(
+            r#"
+            name = "not enforced"
+            not = { field = "disposition", pattern = "enforce", mode = "exact" }
+            "#,
+        );
+        assert!(condition.evaluate(&report()));
+
+        let condition = compile(
+            r#"
+            name = "fonts or scripts"
+            any = [
+                { field = "violated_directive", pattern = "font-src" },
+                { field = "violated_directive", pattern = "script-src" },
+            ]
+            "#,
+        );
+        assert!(!condition.evaluate(&report()));
+    }
+
+    #[test]
+    fn it_does_not_match_missing_fields() {
+        let condition = compile(
+            r#"
+            name = "any source file"
+            field = "source_file"
+            pattern = ".*"
+            mode = "regex"
+            "#,
+        );
+        assert!(!condition.evaluate(&report()));
+    }
+
+    #[test]
+    fn it_rejects_invalid_conditions() {
+        let error = toml::from_str::<RuleConfig>(
+            r#"
+            name = "typo"
+            alll = [{ field = "blocked_uri", pattern = "https://cdn.example" }]
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("unknown field `alll`"));
+
+        let error = toml::from_str::<RuleConfig>(
+            r#"
+            name = "mixed"
+            any = [{ field = "blocked_uri", pattern = "https://cdn.example" }]
+            field = "disposition"
+            pattern = "report"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`any` cannot be combined with field, pattern or mode"));
+
+        let rule: RuleConfig = toml::from_str(
+            r#"
+            name = "empty"
+            not = { all = [] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            "`all` needs at least one condition",
+            rule.compile().unwrap_err().root_cause().to_string()
+        );
+    }
+}
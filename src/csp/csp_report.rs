@@ -1,4 +1,5 @@
 use crate::csp::csp_report_content::CspReportContent;
+use crate::csp::reporting_api::{csp_violations, ReportingApiReport};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -6,3 +7,125 @@ pub struct CspReport {
     #[serde(alias = "csp-report")]
     pub csp_report: CspReportContent,
 }
+
+/// The formats a report can be posted in, depending on its content type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// `application/csp-report`, legacy `report-uri` reports
+    CspReport,
+    /// `application/json`, either legacy reports or Reporting API batches
+    Json,
+    /// `application/reports+json`, Reporting API batches
+    Reports,
+}
+
+impl ReportFormat {
+    /// Find the format matching a Content-Type header value, ignoring its
+    /// parameters (e.g. `; charset=utf-8`)
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+        match essence.as_str() {
+            "application/csp-report" => Some(ReportFormat::CspReport),
+            "application/json" => Some(ReportFormat::Json),
+            "application/reports+json" => Some(ReportFormat::Reports),
+            _ => None,
+        }
+    }
+}
+
+/// The body of a request received on the report endpoint, either a legacy
+/// `report-uri` report or a Reporting API batch.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CspReportPayload {
+    Legacy(Box<CspReport>),
+    ReportingApi(Vec<ReportingApiReport>),
+}
+
+impl CspReportPayload {
+    /// Parse a request body with the parser matching its format
+    pub fn parse(format: ReportFormat, body: &[u8]) -> Result<Self, serde_json::Error> {
+        match format {
+            ReportFormat::CspReport => serde_json::from_slice(body).map(CspReportPayload::Legacy),
+            ReportFormat::Json => serde_json::from_slice(body),
+            ReportFormat::Reports => {
+                serde_json::from_slice(body).map(CspReportPayload::ReportingApi)
+            }
+        }
+    }
+
+    /// Extract the CSP reports contained in the payload
+    pub fn into_csp_report_contents(self) -> Vec<CspReportContent> {
+        match self {
+            CspReportPayload::Legacy(report) => vec![report.csp_report],
+            CspReportPayload::ReportingApi(reports) => csp_violations(reports),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY: &str = r#"{
+        "csp-report": {
+            "blocked-uri": "https://evil.example.com",
+            "document-uri": "https://example.org/",
+            "original-policy": "default-src 'self'",
+            "referrer": "",
+            "violated-directive": "script-src"
+        }
+    }"#;
+
+    const BATCH: &str = r#"[{
+        "type": "csp-violation",
+        "url": "https://example.org/",
+        "body": {
+            "blockedURL": "https://evil.example.com",
+            "documentURL": "https://example.org/",
+            "effectiveDirective": "script-src-elem",
+            "originalPolicy": "default-src 'self'"
+        }
+    }]"#;
+
+    #[test]
+    fn it_finds_the_format_from_content_type() {
+        assert_eq!(
+            Some(ReportFormat::CspReport),
+            ReportFormat::from_content_type("application/csp-report")
+        );
+        assert_eq!(
+            Some(ReportFormat::Json),
+            ReportFormat::from_content_type("Application/JSON; charset=utf-8")
+        );
+        assert_eq!(
+            Some(ReportFormat::Reports),
+            ReportFormat::from_content_type("application/reports+json")
+        );
+        assert_eq!(None, ReportFormat::from_content_type("text/plain"));
+    }
+
+    #[test]
+    fn it_parses_legacy_reports() {
+        for format in [ReportFormat::CspReport, ReportFormat::Json] {
+            let payload = CspReportPayload::parse(format, LEGACY.as_bytes()).unwrap();
+            assert_eq!(1, payload.into_csp_report_contents().len());
+        }
+    }
+
+    #[test]
+    fn it_parses_reporting_api_batches() {
+        for format in [ReportFormat::Reports, ReportFormat::Json] {
+            let payload = CspReportPayload::parse(format, BATCH.as_bytes()).unwrap();
+            assert_eq!(1, payload.into_csp_report_contents().len());
+        }
+    }
+
+    #[test]
+    fn it_rejects_mismatching_bodies() {
+        assert!(CspReportPayload::parse(ReportFormat::CspReport, BATCH.as_bytes()).is_err());
+        assert!(CspReportPayload::parse(ReportFormat::Reports, LEGACY.as_bytes()).is_err());
+        assert!(CspReportPayload::parse(ReportFormat::Json, b"{").is_err());
+    }
+}
@@ -1,11 +1,10 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::csp::filter::{
-    BLOCKED_URI_FILTERS, ORIGINAL_POLICY_FILTERS, REFERRER_FILTERS, SCRIPT_SAMPLE_FILTERS,
-    SOURCE_FILE_FILTERS,
-};
+use crate::csp::condition::Field;
+use crate::csp::matcher::Matcher;
+use crate::csp::rules::{FilterMatch, FilterRules};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CspReportContent {
     /// The URI of the resource that was blocked from loading by the
     /// Content Security Policy. If the blocked URI is from a different
@@ -14,6 +13,15 @@ pub struct CspReportContent {
     #[serde(alias = "blocked-uri")]
     pub blocked_uri: String,
 
+    /// The column number in source-file on which the violation occurred.
+    #[serde(
+        alias = "column-number",
+        alias = "columnNumber",
+        default,
+        deserialize_with = "deserialize_number_or_string"
+    )]
+    pub column_number: Option<u32>,
+
     /// Either "enforce" or "report" depending on whether the
     /// Content-Security-Policy-Report-Only header or
     /// the Content-Security-Policy header is used.
@@ -30,6 +38,15 @@ pub struct CspReportContent {
     #[serde(alias = "effective-directive")]
     pub effective_directive: Option<String>,
 
+    /// The line number in source-file on which the violation occurred.
+    #[serde(
+        alias = "line-number",
+        alias = "lineNumber",
+        default,
+        deserialize_with = "deserialize_number_or_string"
+    )]
+    pub line_number: Option<u32>,
+
     /// The original policy as specified by the Content-Security-Policy
     /// HTTP header.
     #[serde(alias = "original-policy")]
@@ -41,12 +58,16 @@ pub struct CspReportContent {
     /// The first 40 characters of the inline script, event handler, or
     /// style that caused the violation. Only applicable to script-src*
     /// and style-src* violations, when they contain the 'report-sample'
-    #[serde(alias = "script-sample")]
+    #[serde(alias = "script-sample", alias = "sample")]
     pub script_sample: Option<String>,
 
     /// The HTTP status code of the resource on which the global object
     /// was instantiated.
-    #[serde(alias = "status-code")]
+    #[serde(
+        alias = "status-code",
+        default,
+        deserialize_with = "deserialize_string_or_number"
+    )]
     pub status_code: Option<String>,
 
     /// The URL of the resource where the violation occurred, stripped for reporting.
@@ -75,97 +96,205 @@ impl CspReportContent {
             violated_directive: String::from(violated_directive),
             disposition: None,
             effective_directive: None,
+            line_number: None,
+            column_number: None,
             script_sample: None,
             status_code: None,
             source_file: None,
         }
     }
 
-    /// Test if a report is in blocked_uri filters
-    pub fn is_in_blocked_uri_filters(&self) -> bool {
-        BLOCKED_URI_FILTERS.iter().any(|x| *x == self.blocked_uri)
+    /// The position of the violation in the source file, formatted as
+    /// `source-file:line:column`
+    pub fn location(&self) -> Option<String> {
+        let mut location = self.source_file.clone()?;
+
+        if let Some(line_number) = self.line_number {
+            location.push_str(&format!(":{}", line_number));
+
+            if let Some(column_number) = self.column_number {
+                location.push_str(&format!(":{}", column_number));
+            }
+        }
+
+        Some(location)
     }
 
-    /// Test if a report is in original policy filters
-    fn is_in_original_policy_filters(&self) -> bool {
-        ORIGINAL_POLICY_FILTERS
-            .iter()
-            .any(|x| *x == self.original_policy)
+    /// A short human readable summary of where the violation occurred
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Document: {}\nBlocked URI: {}\nViolated directive: {}\n",
+            self.document_uri, self.blocked_uri, self.violated_directive
+        );
+
+        if let Some(location) = self.location() {
+            summary.push_str(&format!("Location: {}\n", location));
+        }
+
+        if let Some(script_sample) = self.script_sample.as_ref().filter(|s| !s.is_empty()) {
+            summary.push_str(&format!("Sample: {}\n", script_sample));
+        }
+
+        summary
     }
 
-    /// Test if a report is in referrer filters
-    fn is_in_referrer_filters(&self) -> bool {
-        REFERRER_FILTERS.iter().any(|x| *x == self.referrer)
+    /// Find the blocked_uri filter matching the report
+    pub fn find_blocked_uri_filter<'a>(&self, rules: &'a FilterRules) -> Option<&'a Matcher> {
+        rules
+            .blocked_uri
+            .iter()
+            .find(|x| x.is_match(&self.blocked_uri))
     }
 
-    /// Test if a report is in script sample filters
-    fn is_in_script_sample_filters(&self) -> bool {
-        SCRIPT_SAMPLE_FILTERS
+    /// Find the original policy filter matching the report
+    fn find_original_policy_filter<'a>(&self, rules: &'a FilterRules) -> Option<&'a Matcher> {
+        rules
+            .original_policy
             .iter()
-            .any(|x| self.script_sample.is_some() && *x == self.script_sample.as_ref().unwrap())
+            .find(|x| x.is_match(&self.original_policy))
+    }
+
+    /// Find the referrer filter matching the report
+    fn find_referrer_filter<'a>(&self, rules: &'a FilterRules) -> Option<&'a Matcher> {
+        rules.referrer.iter().find(|x| x.is_match(&self.referrer))
     }
 
-    /// Test if a report is in original policy filters
-    fn is_in_source_file_filters(&self) -> bool {
-        SOURCE_FILE_FILTERS
+    /// Find the script sample filter matching the report
+    fn find_script_sample_filter<'a>(&self, rules: &'a FilterRules) -> Option<&'a Matcher> {
+        let script_sample = self.script_sample.as_deref()?;
+        rules
+            .script_sample
             .iter()
-            .any(|x| self.source_file.is_some() && *x == self.source_file.as_ref().unwrap())
+            .find(|x| x.is_match(script_sample))
     }
 
-    /// Test if a report is in the block list
-    pub fn is_in_block_list(&self) -> bool {
-        self.is_in_blocked_uri_filters()
-            || self.is_in_original_policy_filters()
-            || self.is_in_referrer_filters()
-            || self.is_in_script_sample_filters()
-            || self.is_in_source_file_filters()
+    /// Find the source file filter matching the report
+    fn find_source_file_filter<'a>(&self, rules: &'a FilterRules) -> Option<&'a Matcher> {
+        let source_file = self.source_file.as_deref()?;
+        rules.source_file.iter().find(|x| x.is_match(source_file))
     }
+
+    /// Find the filter list entry or rule dropping the report, if any
+    pub fn find_filter<'a>(&self, rules: &'a FilterRules) -> Option<FilterMatch<'a>> {
+        self.find_blocked_uri_filter(rules)
+            .map(|m| FilterMatch::list(Field::BlockedUri, m))
+            .or_else(|| {
+                self.find_original_policy_filter(rules)
+                    .map(|m| FilterMatch::list(Field::OriginalPolicy, m))
+            })
+            .or_else(|| {
+                self.find_referrer_filter(rules)
+                    .map(|m| FilterMatch::list(Field::Referrer, m))
+            })
+            .or_else(|| {
+                self.find_script_sample_filter(rules)
+                    .map(|m| FilterMatch::list(Field::ScriptSample, m))
+            })
+            .or_else(|| {
+                self.find_source_file_filter(rules)
+                    .map(|m| FilterMatch::list(Field::SourceFile, m))
+            })
+            .or_else(|| {
+                rules
+                    .rules
+                    .iter()
+                    .find(|rule| rule.condition.evaluate(self))
+                    .map(FilterMatch::rule)
+            })
+    }
+}
+
+/// Browsers send some fields either as strings or as numbers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(u64),
+}
+
+/// Deserialize an optional string field which may be sent as a number
+pub fn deserialize_string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<StringOrNumber>::deserialize(deserializer)?.map(|value| match value {
+            StringOrNumber::String(value) => value,
+            StringOrNumber::Number(value) => value.to_string(),
+        }),
+    )
+}
+
+/// Deserialize an optional number field which may be sent as a string. A
+/// value which is not a valid number is ignored.
+pub fn deserialize_number_or_string<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<StringOrNumber>::deserialize(deserializer)? {
+        None => None,
+        Some(StringOrNumber::Number(value)) => u32::try_from(value).ok(),
+        Some(StringOrNumber::String(value)) => value.trim().parse().ok(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ConfigFormat;
 
     #[test]
     fn it_is_in_blocked_uri_filters() {
         let blocked_uri = "mxjscall://";
         let csp_c = CspReportContent::default(blocked_uri, "", "", "", "");
-        assert_eq!(true, csp_c.is_in_blocked_uri_filters());
+        assert!(csp_c
+            .find_blocked_uri_filter(&FilterRules::default())
+            .is_some());
     }
 
     #[test]
     fn it_is_not_in_blocked_uri_filters() {
         let blocked_uri = "https://fonts.googleapis.com";
         let csp_c = CspReportContent::default(blocked_uri, "", "", "", "");
-        assert_eq!(false, csp_c.is_in_blocked_uri_filters());
+        assert!(csp_c
+            .find_blocked_uri_filter(&FilterRules::default())
+            .is_none());
     }
 
     #[test]
     fn it_is_in_original_policy_filters() {
         let original_policy = ".blackspider.com";
         let csp_c = CspReportContent::default("", "", original_policy, "", "");
-        assert_eq!(true, csp_c.is_in_original_policy_filters());
+        assert!(csp_c
+            .find_original_policy_filter(&FilterRules::default())
+            .is_some());
     }
 
     #[test]
     fn it_is_not_in_original_policy_filters() {
         let original_policy = "https://fonts.googleapis.com";
         let csp_c = CspReportContent::default("", "", original_policy, "", "");
-        assert_eq!(false, csp_c.is_in_original_policy_filters());
+        assert!(csp_c
+            .find_original_policy_filter(&FilterRules::default())
+            .is_none());
     }
 
     #[test]
     fn it_is_in_referrer_filters() {
         let referrer = "http://l.facebook.com";
         let csp_c = CspReportContent::default("", "", "", referrer, "");
-        assert_eq!(true, csp_c.is_in_referrer_filters());
+        assert!(csp_c
+            .find_referrer_filter(&FilterRules::default())
+            .is_some());
     }
 
     #[test]
     fn it_is_not_in_referrer_filters() {
         let referrer = "https://fonts.googleapis.com";
         let csp_c = CspReportContent::default("", "", "", referrer, "");
-        assert_eq!(false, csp_c.is_in_referrer_filters());
+        assert!(csp_c
+            .find_referrer_filter(&FilterRules::default())
+            .is_none());
     }
 
     #[test]
@@ -173,7 +302,9 @@ mod tests {
         let script_sample = "var BlockAdBlock = function";
         let mut csp_c = CspReportContent::default("", "", "", "", "");
         csp_c.script_sample = Some(String::from(script_sample));
-        assert_eq!(true, csp_c.is_in_script_sample_filters());
+        assert!(csp_c
+            .find_script_sample_filter(&FilterRules::default())
+            .is_some());
     }
 
     #[test]
@@ -181,7 +312,9 @@ mod tests {
         let script_sample = "console.log('Rust is great!');'";
         let mut csp_c = CspReportContent::default("", "", "", "", "");
         csp_c.script_sample = Some(String::from(script_sample));
-        assert_eq!(false, csp_c.is_in_script_sample_filters());
+        assert!(csp_c
+            .find_script_sample_filter(&FilterRules::default())
+            .is_none());
     }
 
     #[test]
@@ -189,7 +322,9 @@ mod tests {
         let source_file = "chromenull://";
         let mut csp_c = CspReportContent::default("", "", "", "", "");
         csp_c.source_file = Some(String::from(source_file));
-        assert_eq!(true, csp_c.is_in_source_file_filters());
+        assert!(csp_c
+            .find_source_file_filter(&FilterRules::default())
+            .is_some());
     }
 
     #[test]
@@ -197,6 +332,123 @@ mod tests {
         let source_file = "fonts.googleapis.com";
         let mut csp_c = CspReportContent::default("", "", "", "", "");
         csp_c.source_file = Some(String::from(source_file));
-        assert_eq!(false, csp_c.is_in_source_file_filters());
+        assert!(csp_c
+            .find_source_file_filter(&FilterRules::default())
+            .is_none());
+    }
+
+    #[test]
+    fn it_accepts_numbers_and_strings() {
+        let csp_c: CspReportContent = serde_json::from_str(
+            r#"{
+                "blocked-uri": "inline",
+                "document-uri": "https://example.org/",
+                "original-policy": "default-src 'self'",
+                "referrer": "",
+                "violated-directive": "script-src",
+                "status-code": 200,
+                "source-file": "https://example.org/app.js",
+                "line-number": "12",
+                "column-number": 5,
+                "sample": "alert(1)"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("200"), csp_c.status_code.as_deref());
+        assert_eq!(Some(12), csp_c.line_number);
+        assert_eq!(Some(5), csp_c.column_number);
+        assert_eq!(Some("alert(1)"), csp_c.script_sample.as_deref());
+        assert_eq!(
+            Some("https://example.org/app.js:12:5"),
+            csp_c.location().as_deref()
+        );
+
+        let csp_c: CspReportContent = serde_json::from_str(
+            r#"{
+                "blocked-uri": "inline",
+                "document-uri": "https://example.org/",
+                "original-policy": "default-src 'self'",
+                "referrer": "",
+                "violated-directive": "script-src",
+                "line-number": "unknown",
+                "column-number": 4294967296
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(None, csp_c.line_number);
+        assert_eq!(None, csp_c.column_number);
+    }
+
+    #[test]
+    fn it_matches_builtin_filters_as_prefixes() {
+        let blocked_uri = "chrome-extension://abcdefgh/content.js";
+        let csp_c = CspReportContent::default(blocked_uri, "", "", "", "");
+        assert!(csp_c
+            .find_blocked_uri_filter(&FilterRules::default())
+            .is_some());
+
+        let script_sample = "var FuckAdBlock = function(options) {";
+        let mut csp_c = CspReportContent::default("", "", "", "", "");
+        csp_c.script_sample = Some(String::from(script_sample));
+        assert!(csp_c
+            .find_script_sample_filter(&FilterRules::default())
+            .is_some());
+    }
+
+    #[test]
+    fn it_does_not_widen_builtin_keywords_and_hosts() {
+        let csp_c = CspReportContent::default("null", "", "", "", "");
+        assert!(csp_c
+            .find_blocked_uri_filter(&FilterRules::default())
+            .is_some());
+
+        for blocked_uri in [
+            "https://example.com/nullable.js",
+            "https://d111111abcdef8.cloudfront.net/app.js",
+            "https://www.googletagmanager.com/gtm.js",
+        ] {
+            let csp_c = CspReportContent::default(blocked_uri, "", "", "", "");
+            assert!(csp_c
+                .find_blocked_uri_filter(&FilterRules::default())
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn it_finds_the_blocking_rule() {
+        let rules = FilterRules::parse(
+            r#"
+            [[rules]]
+            name = "legacy checkout"
+            field = "document_uri"
+            pattern = "https://example.org/checkout/"
+            mode = "prefix"
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+
+        let csp_c = CspReportContent::default("mxjscall://", "", "", "", "");
+        let filter = csp_c.find_filter(&rules).unwrap();
+        assert_eq!("blocked_uri", filter.rule);
+        assert_eq!(Some(Field::BlockedUri), filter.field);
+        assert_eq!(
+            Some("mxjscall://"),
+            filter.matcher.map(|m| m.pattern.as_str())
+        );
+
+        let csp_c = CspReportContent::default("", "https://example.org/checkout/", "", "", "");
+        let filter = csp_c.find_filter(&rules).unwrap();
+        assert_eq!("legacy checkout", filter.rule);
+        assert_eq!(Some(Field::DocumentUri), filter.field);
+        assert_eq!(
+            "rule \"legacy checkout\" (document_uri prefix \"https://example.org/checkout/\")",
+            filter.to_string()
+        );
+
+        let csp_c = CspReportContent::default("", "https://example.org/", "", "", "");
+        assert_eq!(None, csp_c.find_filter(&rules));
     }
 }
@@ -1,7 +1,6 @@
 /// Init some filters, usefull to exlude some of the reports you might receive.
 /// This is basically false positives, or browser extension causing some trouble.
 /// Shamelessly taken from <https://github.com/nico3333fr/CSP-useful/tree/master/csp-wtf>
-
 pub const SOURCE_FILE_FILTERS: &[&str] = &[
     "chromenull://",
     "resource://",
@@ -113,7 +112,6 @@ pub const BLOCKED_URI_FILTERS: &[&str] = &[
     "spedcheck.space",
     "ffoodd/a11y.css",
     "canvaspl-a.akamaihd.net",
-    "savingsslider-a.akamaihd.net",
     "www.ciuvo.com",
     "loadingpabes.info",
     "loadingpageson.science",
@@ -143,11 +141,9 @@ pub const BLOCKED_URI_FILTERS: &[&str] = &[
     "cdnclntr.com",
     "cdnjs.space",
     "cdnlvry.xyz",
-    "cdnnetwok.xyz",
     "cdnstr.xyz",
     "cdnswf.xyz",
     "cdnvalid.xyz",
-    "connectionstrenth.com",
     "domainanalyzing.xyz",
     "domaincdn.xyz",
     "domainvalidation.xyz",
@@ -199,10 +195,8 @@ pub const BLOCKED_URI_FILTERS: &[&str] = &[
     "www9.thrgh.space",
     "ydpi.pw",
     "getstencil.com",
-    "elsewheregames.com",
     "fungamesshop.com",
     "gameandslash.com",
-    "gamephrase.com",
     "gamersurface.com",
     "pinballpal.com",
     "mozbar.moz.com",
@@ -260,7 +254,6 @@ pub const SCRIPT_SAMPLE_FILTERS: &[&str] = &[
     "background-image: url(resource://jid1",
     "#forecastfox",
     ".minvid__overlay__container",
-    ".password-toggler-button-parent",
     "#toggleGifsOverlay",
     "function s(t,n,e,r){t",
     "onclick=\"fileice()\"",
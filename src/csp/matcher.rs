@@ -0,0 +1,152 @@
+use anyhow::{Context, Error};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a filter entry is compared to a report field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// The field is equal to the pattern
+    Exact,
+    /// The field starts with the pattern
+    Prefix,
+    /// The field contains the pattern
+    #[default]
+    Contains,
+    /// The field matches a glob pattern, where `*` matches any sequence of
+    /// characters and `?` a single character
+    Glob,
+    /// The field matches a regular expression
+    Regex,
+}
+
+/// A pattern compiled according to its match mode
+#[derive(Debug, Clone)]
+enum CompiledPattern {
+    Text,
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+/// A filter entry, tested against a single report field
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub pattern: String,
+    pub mode: MatchMode,
+    compiled: CompiledPattern,
+}
+
+impl Matcher {
+    /// Compile a pattern with the given match mode
+    pub fn new(pattern: &str, mode: MatchMode) -> Result<Self, Error> {
+        let compiled = match mode {
+            MatchMode::Exact | MatchMode::Prefix | MatchMode::Contains => CompiledPattern::Text,
+            MatchMode::Glob => CompiledPattern::Glob(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid glob pattern {:?}", pattern))?
+                    .compile_matcher(),
+            ),
+            MatchMode::Regex => CompiledPattern::Regex(
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid regex pattern {:?}", pattern))?,
+            ),
+        };
+
+        Ok(Matcher {
+            pattern: String::from(pattern),
+            mode,
+            compiled,
+        })
+    }
+
+    /// A matcher using the default mode, which never fails to compile
+    #[cfg(test)]
+    pub fn contains(pattern: &str) -> Self {
+        Matcher {
+            pattern: String::from(pattern),
+            mode: MatchMode::Contains,
+            compiled: CompiledPattern::Text,
+        }
+    }
+
+    /// Test if a report field matches the pattern
+    pub fn is_match(&self, value: &str) -> bool {
+        match (&self.compiled, self.mode) {
+            (CompiledPattern::Glob(glob), _) => glob.is_match(value),
+            (CompiledPattern::Regex(regex), _) => regex.is_match(value),
+            (CompiledPattern::Text, MatchMode::Exact) => value == self.pattern,
+            (CompiledPattern::Text, MatchMode::Prefix) => value.starts_with(&self.pattern),
+            (CompiledPattern::Text, _) => value.contains(&self.pattern),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            MatchMode::Exact => "exact",
+            MatchMode::Prefix => "prefix",
+            MatchMode::Contains => "contains",
+            MatchMode::Glob => "glob",
+            MatchMode::Regex => "regex",
+        };
+
+        write!(f, "{} {:?}", mode, self.pattern)
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.mode == other.mode
+    }
+}
+
+impl Eq for Matcher {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_exact_values() {
+        let matcher = Matcher::new("null", MatchMode::Exact).unwrap();
+        assert!(matcher.is_match("null"));
+        assert!(!matcher.is_match("nullable"));
+    }
+
+    #[test]
+    fn it_matches_prefixes() {
+        let matcher = Matcher::new("chrome-extension:/", MatchMode::Prefix).unwrap();
+        assert!(matcher.is_match("chrome-extension://abc/x.js"));
+        assert!(!matcher.is_match("https://example.org/chrome-extension:/"));
+    }
+
+    #[test]
+    fn it_matches_substrings() {
+        let matcher = Matcher::contains("192.168.1");
+        assert!(matcher.is_match("http://192.168.1.12/ad.js"));
+        assert!(!matcher.is_match("http://10.0.0.1/ad.js"));
+    }
+
+    #[test]
+    fn it_matches_globs() {
+        let matcher = Matcher::new("https://*.example.com/*", MatchMode::Glob).unwrap();
+        assert!(matcher.is_match("https://cdn.example.com/js/app.js"));
+        assert!(!matcher.is_match("https://example.org/js/app.js"));
+    }
+
+    #[test]
+    fn it_matches_regexes() {
+        let matcher = Matcher::new(r"^https://[a-z0-9]+\.cdn\.net/", MatchMode::Regex).unwrap();
+        assert!(matcher.is_match("https://d1x2.cdn.net/x.js"));
+        assert!(!matcher.is_match("http://d1x2.cdn.net/x.js"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        assert!(Matcher::new("(", MatchMode::Regex).is_err());
+        assert!(Matcher::new("[", MatchMode::Glob).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+pub mod condition;
 pub mod csp_report;
 pub mod csp_report_content;
 pub mod filter;
+pub mod matcher;
+pub mod received_report;
+pub mod reporting_api;
+pub mod rules;
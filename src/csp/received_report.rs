@@ -0,0 +1,46 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::csp::csp_report_content::CspReportContent;
+
+/// A report, along with the context in which it was received
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceivedReport {
+    pub csp_report: CspReportContent,
+
+    /// When morbo received the report
+    pub received_at: DateTime<Utc>,
+
+    /// The User-Agent of the browser which sent the report
+    pub user_agent: Option<String>,
+
+    /// The slug of the project the report was sent to, if any
+    pub project: Option<String>,
+
+    /// The filter which dropped the report, if any
+    pub filtered_by: Option<String>,
+
+    /// Identifies the violation of the report, when duplicates are
+    /// suppressed
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+
+    /// How many more times the violation was reported, and suppressed as
+    /// duplicates of this report
+    #[serde(default)]
+    pub duplicates: u64,
+}
+
+impl ReceivedReport {
+    pub fn new(csp_report: CspReportContent) -> Self {
+        ReceivedReport {
+            csp_report,
+            received_at: Utc::now(),
+            user_agent: None,
+            project: None,
+            filtered_by: None,
+            fingerprint: None,
+            duplicates: 0,
+        }
+    }
+}
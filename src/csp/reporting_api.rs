@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::csp::csp_report_content::{
+    deserialize_number_or_string, deserialize_string_or_number, CspReportContent,
+};
+
+/// Type of the Reporting API reports carrying a CSP violation
+pub const CSP_VIOLATION_TYPE: &str = "csp-violation";
+
+/// A single report sent through the Reporting API (`report-to` directive),
+/// as delivered in an `application/reports+json` batch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportingApiReport {
+    /// The type of report, "csp-violation" for CSP reports.
+    #[serde(rename = "type")]
+    pub report_type: String,
+
+    /// The number of milliseconds between the report's timestamp and the
+    /// current time.
+    pub age: Option<u64>,
+
+    /// The URL of the document that generated the report.
+    pub url: String,
+
+    /// The User-Agent of the browser that generated the report.
+    pub user_agent: Option<String>,
+
+    /// The body of the report, whose shape depends on the report type.
+    pub body: serde_json::Value,
+}
+
+/// The body of a "csp-violation" report, as sent through the Reporting API.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CspViolationReportBody {
+    /// The URL of the resource that was blocked.
+    #[serde(rename = "blockedURL")]
+    pub blocked_url: Option<String>,
+
+    /// Either "enforce" or "report".
+    pub disposition: Option<String>,
+
+    /// The URL of the document in which the violation occurred.
+    #[serde(rename = "documentURL")]
+    pub document_url: String,
+
+    /// The directive whose enforcement caused the violation.
+    pub effective_directive: String,
+
+    /// The line number in the source file on which the violation occurred.
+    #[serde(default, deserialize_with = "deserialize_number_or_string")]
+    pub line_number: Option<u32>,
+
+    /// The column number in the source file on which the violation occurred.
+    #[serde(default, deserialize_with = "deserialize_number_or_string")]
+    pub column_number: Option<u32>,
+
+    /// The policy whose enforcement caused the violation.
+    pub original_policy: String,
+
+    /// The referrer of the document in which the violation occurred.
+    pub referrer: Option<String>,
+
+    /// The first 40 characters of the inline script, event handler, or
+    /// style that caused the violation.
+    pub sample: Option<String>,
+
+    /// The URL of the resource where the violation occurred.
+    pub source_file: Option<String>,
+
+    /// The HTTP status code of the document in which the violation occurred.
+    #[serde(default, deserialize_with = "deserialize_string_or_number")]
+    pub status_code: Option<String>,
+}
+
+impl From<CspViolationReportBody> for CspReportContent {
+    fn from(body: CspViolationReportBody) -> Self {
+        // The Reporting API has no violated directive anymore, the effective
+        // directive takes its place.
+        let mut content = CspReportContent::default(
+            body.blocked_url.as_deref().unwrap_or_default(),
+            &body.document_url,
+            &body.original_policy,
+            body.referrer.as_deref().unwrap_or_default(),
+            &body.effective_directive,
+        );
+
+        content.disposition = body.disposition;
+        content.effective_directive = Some(body.effective_directive);
+        content.line_number = body.line_number;
+        content.column_number = body.column_number;
+        content.script_sample = body.sample;
+        content.source_file = body.source_file;
+        content.status_code = body.status_code;
+
+        content
+    }
+}
+
+impl ReportingApiReport {
+    /// Test if the report is a CSP violation
+    pub fn is_csp_violation(&self) -> bool {
+        self.report_type == CSP_VIOLATION_TYPE
+    }
+}
+
+/// Extract the CSP violations of a Reporting API batch, normalized as
+/// CspReportContent. Other report types and malformed bodies are skipped.
+pub fn csp_violations(reports: Vec<ReportingApiReport>) -> Vec<CspReportContent> {
+    reports
+        .into_iter()
+        .filter(|report| report.is_csp_violation())
+        .filter_map(
+            |report| match serde_json::from_value::<CspViolationReportBody>(report.body) {
+                Ok(body) => Some(CspReportContent::from(body)),
+                Err(e) => {
+                    warn!(
+                        "Skipping malformed csp-violation from {}: {}",
+                        report.url, e
+                    );
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BATCH: &str = r#"[
+        {
+            "type": "csp-violation",
+            "age": 53531,
+            "url": "https://example.org/checkout",
+            "user_agent": "Mozilla/5.0",
+            "body": {
+                "blockedURL": "https://evil.example.com/script.js",
+                "disposition": "enforce",
+                "documentURL": "https://example.org/checkout",
+                "effectiveDirective": "script-src-elem",
+                "originalPolicy": "default-src 'self'; report-to csp-endpoint",
+                "referrer": "https://example.org/",
+                "sample": "alert(1)",
+                "sourceFile": "https://example.org/app.js",
+                "lineNumber": 12,
+                "columnNumber": 5,
+                "statusCode": 200
+            }
+        },
+        {
+            "type": "deprecation",
+            "age": 12,
+            "url": "https://example.org/",
+            "user_agent": "Mozilla/5.0",
+            "body": { "id": "websql", "message": "WebSQL is deprecated" }
+        }
+    ]"#;
+
+    #[test]
+    fn it_normalizes_csp_violations() {
+        let reports: Vec<ReportingApiReport> = serde_json::from_str(BATCH).unwrap();
+        let contents = csp_violations(reports);
+
+        assert_eq!(1, contents.len());
+
+        let content = &contents[0];
+        assert_eq!("https://evil.example.com/script.js", content.blocked_uri);
+        assert_eq!("https://example.org/checkout", content.document_uri);
+        assert_eq!("script-src-elem", content.violated_directive);
+        assert_eq!(
+            Some("script-src-elem"),
+            content.effective_directive.as_deref()
+        );
+        assert_eq!(Some("enforce"), content.disposition.as_deref());
+        assert_eq!(Some("200"), content.status_code.as_deref());
+        assert_eq!(Some("alert(1)"), content.script_sample.as_deref());
+        assert_eq!(
+            Some("https://example.org/app.js:12:5"),
+            content.location().as_deref()
+        );
+    }
+
+    #[test]
+    fn it_accepts_numbers_as_strings() {
+        let batch = r#"[{
+            "type": "csp-violation",
+            "url": "https://example.org/",
+            "body": {
+                "documentURL": "https://example.org/",
+                "effectiveDirective": "script-src",
+                "originalPolicy": "default-src 'self'",
+                "sourceFile": "https://example.org/app.js",
+                "lineNumber": "12",
+                "columnNumber": "n/a",
+                "statusCode": "200"
+            }
+        }]"#;
+        let reports: Vec<ReportingApiReport> = serde_json::from_str(batch).unwrap();
+        let contents = csp_violations(reports);
+
+        assert_eq!(1, contents.len());
+        assert_eq!(Some(12), contents[0].line_number);
+        assert_eq!(None, contents[0].column_number);
+        assert_eq!(Some("200"), contents[0].status_code.as_deref());
+    }
+
+    #[test]
+    fn it_skips_malformed_csp_violations() {
+        let batch = r#"[{"type": "csp-violation", "url": "https://example.org/", "body": {}}]"#;
+        let reports: Vec<ReportingApiReport> = serde_json::from_str(batch).unwrap();
+        assert!(csp_violations(reports).is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use serde::Deserialize;
+
+use crate::config::{self, ConfigFormat};
+use crate::csp::condition::{Condition, Field, Rule, RuleConfig};
+use crate::csp::filter::{
+    BLOCKED_URI_FILTERS, ORIGINAL_POLICY_FILTERS, REFERRER_FILTERS, SCRIPT_SAMPLE_FILTERS,
+    SOURCE_FILE_FILTERS,
+};
+use crate::csp::matcher::{MatchMode, Matcher};
+
+/// The filters a report is tested against before being sent to the channels
+#[derive(Debug, Clone)]
+pub struct FilterRules {
+    pub source_file: Vec<Matcher>,
+    pub blocked_uri: Vec<Matcher>,
+    pub script_sample: Vec<Matcher>,
+    pub referrer: Vec<Matcher>,
+    pub original_policy: Vec<Matcher>,
+
+    /// Named rules combining several fields
+    pub rules: Vec<Rule>,
+}
+
+impl Default for FilterRules {
+    /// The built-in csp-wtf filters
+    fn default() -> Self {
+        FilterRules {
+            source_file: to_matchers(SOURCE_FILE_FILTERS, builtin_mode),
+            blocked_uri: to_matchers(BLOCKED_URI_FILTERS, builtin_mode),
+            // Samples are the first characters of the code
+            script_sample: to_matchers(SCRIPT_SAMPLE_FILTERS, |_| MatchMode::Prefix),
+            referrer: to_matchers(REFERRER_FILTERS, builtin_mode),
+            original_policy: to_matchers(ORIGINAL_POLICY_FILTERS, builtin_mode),
+            rules: vec![],
+        }
+    }
+}
+
+/// The match mode of a built-in entry: schemes and URLs are prefixes, and
+/// the other entries, like `null` or host names, are compared exactly
+fn builtin_mode(filter: &str) -> MatchMode {
+    if filter.contains(":/") || filter.starts_with("jar:") {
+        MatchMode::Prefix
+    } else {
+        MatchMode::Exact
+    }
+}
+
+fn to_matchers(filters: &[&str], mode: fn(&str) -> MatchMode) -> Vec<Matcher> {
+    filters
+        .iter()
+        .map(|filter| Matcher::new(filter, mode(filter)).expect("Text patterns always compile"))
+        .collect()
+}
+
+/// A filter list entry or a rule matching a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterMatch<'a> {
+    /// The name of the filter list or rule
+    pub rule: &'a str,
+
+    /// The field tested, for filter lists and single field rules
+    pub field: Option<Field>,
+
+    /// The entry which matched, for filter lists and single field rules
+    pub matcher: Option<&'a Matcher>,
+}
+
+impl<'a> FilterMatch<'a> {
+    /// A match of a filter list entry
+    pub fn list(field: Field, matcher: &'a Matcher) -> Self {
+        FilterMatch {
+            rule: field.as_str(),
+            field: Some(field),
+            matcher: Some(matcher),
+        }
+    }
+
+    /// A match of a rule
+    pub fn rule(rule: &'a Rule) -> Self {
+        let (field, matcher) = match &rule.condition {
+            Condition::Match { field, matcher } => (Some(*field), Some(matcher)),
+            _ => (None, None),
+        };
+
+        FilterMatch {
+            rule: &rule.name,
+            field,
+            matcher,
+        }
+    }
+}
+
+impl fmt::Display for FilterMatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule {:?}", self.rule)?;
+
+        if let (Some(field), Some(matcher)) = (self.field, self.matcher) {
+            write!(f, " ({} {})", field.as_str(), matcher)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A rules file, as written by the user
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    /// Start from the built-in csp-wtf filters
+    #[serde(default = "default_defaults")]
+    defaults: bool,
+
+    #[serde(default)]
+    source_file: RulesList,
+    #[serde(default)]
+    blocked_uri: RulesList,
+    #[serde(default)]
+    script_sample: RulesList,
+    #[serde(default)]
+    referrer: RulesList,
+    #[serde(default)]
+    original_policy: RulesList,
+
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+fn default_defaults() -> bool {
+    true
+}
+
+/// How a rules file changes one of the filter lists
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesList {
+    /// Entries replacing the whole list
+    replace: Option<Vec<RulesEntry>>,
+
+    /// Entries added to the list
+    #[serde(default)]
+    extend: Vec<RulesEntry>,
+}
+
+/// A filter entry, either a bare pattern using the default match mode, or a
+/// pattern with its match mode
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RulesEntry {
+    Pattern(String),
+    WithMode {
+        pattern: String,
+        #[serde(default)]
+        mode: MatchMode,
+    },
+}
+
+impl RulesEntry {
+    fn compile(self) -> Result<Matcher, Error> {
+        match self {
+            RulesEntry::Pattern(pattern) => Matcher::new(&pattern, MatchMode::default()),
+            RulesEntry::WithMode { pattern, mode } => Matcher::new(&pattern, mode),
+        }
+    }
+}
+
+impl RulesList {
+    fn apply(self, list: &mut Vec<Matcher>) -> Result<(), Error> {
+        if let Some(replace) = self.replace {
+            *list = compile_entries(replace)?;
+        }
+
+        list.extend(compile_entries(self.extend)?);
+        dedup(list);
+
+        Ok(())
+    }
+}
+
+/// Remove the entries listed twice, keeping the first one, so each entry
+/// has its own hit count
+fn dedup(list: &mut Vec<Matcher>) {
+    let mut index = 0;
+    while index < list.len() {
+        if list[..index].contains(&list[index]) {
+            list.remove(index);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+fn compile_entries(entries: Vec<RulesEntry>) -> Result<Vec<Matcher>, Error> {
+    entries.into_iter().map(RulesEntry::compile).collect()
+}
+
+impl FilterRules {
+    /// Filter rules without any entry
+    pub fn empty() -> Self {
+        FilterRules {
+            source_file: vec![],
+            blocked_uri: vec![],
+            script_sample: vec![],
+            referrer: vec![],
+            original_policy: vec![],
+            rules: vec![],
+        }
+    }
+
+    /// Every filter list entry and rule, in evaluation order
+    pub fn entries(&self) -> Vec<FilterMatch<'_>> {
+        let lists = [
+            (Field::BlockedUri, &self.blocked_uri),
+            (Field::OriginalPolicy, &self.original_policy),
+            (Field::Referrer, &self.referrer),
+            (Field::ScriptSample, &self.script_sample),
+            (Field::SourceFile, &self.source_file),
+        ];
+
+        lists
+            .into_iter()
+            .flat_map(|(field, list)| {
+                list.iter()
+                    .map(move |matcher| FilterMatch::list(field, matcher))
+            })
+            .chain(self.rules.iter().map(FilterMatch::rule))
+            .collect()
+    }
+
+    /// Load the filter rules from a TOML or YAML file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        config::load(path, Self::parse)
+    }
+
+    /// Parse the content of a rules file
+    pub fn parse(content: &str, format: ConfigFormat) -> Result<Self, Error> {
+        let file: RulesFile = format.parse(content)?;
+
+        let mut rules = if file.defaults {
+            FilterRules::default()
+        } else {
+            FilterRules::empty()
+        };
+
+        file.source_file
+            .apply(&mut rules.source_file)
+            .context("Invalid source_file filters")?;
+        file.blocked_uri
+            .apply(&mut rules.blocked_uri)
+            .context("Invalid blocked_uri filters")?;
+        file.script_sample
+            .apply(&mut rules.script_sample)
+            .context("Invalid script_sample filters")?;
+        file.referrer
+            .apply(&mut rules.referrer)
+            .context("Invalid referrer filters")?;
+        file.original_policy
+            .apply(&mut rules.original_policy)
+            .context("Invalid original_policy filters")?;
+
+        rules.rules = file
+            .rules
+            .into_iter()
+            .map(RuleConfig::compile)
+            .collect::<Result<_, _>>()?;
+
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_extends_the_default_rules() {
+        let rules = FilterRules::parse(
+            r#"
+            [blocked_uri]
+            extend = ["https://noisy.example.com"]
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+
+        assert_eq!(BLOCKED_URI_FILTERS.len() + 1, rules.blocked_uri.len());
+        assert_eq!(
+            Some("https://noisy.example.com"),
+            rules
+                .blocked_uri
+                .last()
+                .map(|matcher| matcher.pattern.as_str())
+        );
+        assert_eq!(FilterRules::default().referrer, rules.referrer);
+    }
+
+    #[test]
+    fn it_lists_each_entry_once() {
+        let defaults = FilterRules::default();
+        for list in [
+            &defaults.blocked_uri,
+            &defaults.original_policy,
+            &defaults.referrer,
+            &defaults.script_sample,
+            &defaults.source_file,
+        ] {
+            let mut deduped = list.clone();
+            dedup(&mut deduped);
+            assert_eq!(list, &deduped);
+        }
+
+        let rules = FilterRules::parse(
+            r#"
+            [blocked_uri]
+            extend = ["null", "https://noisy.example.com", "https://noisy.example.com"]
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(BLOCKED_URI_FILTERS.len() + 2, rules.blocked_uri.len());
+    }
+
+    #[test]
+    fn it_replaces_a_default_list() {
+        let rules = FilterRules::parse(
+            r#"
+            referrer:
+              replace: ["https://l.example.com"]
+            "#,
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![Matcher::contains("https://l.example.com")],
+            rules.referrer
+        );
+        assert_eq!(FilterRules::default().blocked_uri, rules.blocked_uri);
+    }
+
+    #[test]
+    fn it_drops_the_default_rules() {
+        let rules = FilterRules::parse(
+            r#"
+            defaults = false
+
+            [source_file]
+            extend = ["https://example.org/legacy.js"]
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![Matcher::contains("https://example.org/legacy.js")],
+            rules.source_file
+        );
+        assert!(rules.blocked_uri.is_empty());
+    }
+
+    #[test]
+    fn it_reads_match_modes() {
+        let rules = FilterRules::parse(
+            r#"
+            [blocked_uri]
+            extend = [
+                "https://noisy.example.com",
+                { pattern = "https://*.tracker.net", mode = "glob" },
+                { pattern = "^wss?://", mode = "regex" },
+            ]
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+
+        let modes: Vec<MatchMode> = rules
+            .blocked_uri
+            .iter()
+            .rev()
+            .take(3)
+            .map(|matcher| matcher.mode)
+            .collect();
+        assert_eq!(
+            vec![MatchMode::Regex, MatchMode::Glob, MatchMode::Contains],
+            modes
+        );
+    }
+
+    #[test]
+    fn it_reads_composite_rules() {
+        let rules = FilterRules::parse(
+            r#"
+            [[rules]]
+            name = "staging images"
+            all = [
+                { field = "effective_directive", pattern = "img-src", mode = "exact" },
+                { field = "document_host", pattern = "staging.*", mode = "glob" },
+            ]
+
+            [[rules]]
+            name = "report only"
+            not = { field = "disposition", pattern = "enforce", mode = "exact" }
+            "#,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+
+        let names: Vec<&str> = rules.rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(vec!["staging images", "report only"], names);
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        let rules = FilterRules::parse(
+            "[referrer]\nextend = [{ pattern = \"(\", mode = \"regex\" }]",
+            ConfigFormat::Toml,
+        );
+        assert!(rules.is_err());
+    }
+
+    #[test]
+    fn it_rejects_unknown_lists() {
+        let rules = FilterRules::parse("[blocked_uris]\nextend = []", ConfigFormat::Toml);
+        assert!(rules.is_err());
+    }
+}
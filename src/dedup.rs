@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error};
+use url::Url;
+
+use crate::csp::csp_report_content::CspReportContent;
+use crate::csp::received_report::ReceivedReport;
+use crate::dispatcher::Dispatcher;
+
+/// Interval between two checks for the windows which ended
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The origin of the blocked URI, or its keyword (`inline`, `eval`...) or
+/// scheme (`data:`...) when it has no host
+fn blocked_origin(blocked_uri: &str) -> String {
+    match Url::parse(blocked_uri) {
+        Ok(url) if url.has_host() => url.origin().ascii_serialization(),
+        Ok(url) => format!("{}:", url.scheme()),
+        Err(_) => blocked_uri.to_lowercase(),
+    }
+}
+
+/// Test if a path segment looks like an identifier: a number, a UUID or a
+/// long hexadecimal hash
+fn is_identifier(segment: &str) -> bool {
+    let digits = segment.chars().filter(char::is_ascii_digit).count();
+
+    (!segment.is_empty() && digits == segment.len())
+        || (segment.len() >= 8
+            && digits > 0
+            && segment.chars().all(|c| c.is_ascii_hexdigit() || c == '-'))
+}
+
+/// The host and path of the document, with identifiers replaced by `{id}`,
+/// so every page of a template shares the same fingerprint
+fn document_template(document_uri: &str) -> String {
+    let url = match Url::parse(document_uri) {
+        Ok(url) => url,
+        Err(_) => return document_uri.to_string(),
+    };
+
+    let path = url
+        .path()
+        .split('/')
+        .map(|segment| {
+            if is_identifier(segment) {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("{}{}", url.host_str().unwrap_or_default(), path)
+}
+
+/// The source file, without its query string and fragment
+fn source_file(source_file: &str) -> &str {
+    source_file.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// 64 bits FNV-1a hash, stable across versions and restarts
+fn fnv1a(data: &str) -> u64 {
+    data.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Identify the violation of a report: reports with the same fingerprint
+/// come from the same directive, blocked origin, document template and
+/// source location
+pub fn fingerprint(project: Option<&str>, report: &CspReportContent) -> String {
+    let directive = report
+        .effective_directive
+        .as_deref()
+        .unwrap_or(&report.violated_directive);
+    // Old browsers send the whole directive, with its sources
+    let directive = directive
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let fields = [
+        project.unwrap_or_default().to_string(),
+        directive,
+        blocked_origin(&report.blocked_uri),
+        document_template(&report.document_uri),
+        report
+            .source_file
+            .as_deref()
+            .map(source_file)
+            .unwrap_or_default()
+            .to_string(),
+        report
+            .line_number
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+        report
+            .column_number
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+    ];
+
+    format!("{:016x}", fnv1a(&fields.join("\n")))
+}
+
+/// The duplicates of a report suppressed during its window
+#[derive(Debug)]
+struct Suppressed {
+    window_end: Instant,
+
+    /// The last duplicate, forwarded at the end of the window
+    last: Option<ReceivedReport>,
+    count: u64,
+}
+
+/// Suppresses the reports of a violation already sent during the window,
+/// and forwards a single report with their count at the end of the window
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    suppressed: Mutex<HashMap<String, Suppressed>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            suppressed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fingerprint a report, and return whether it must be sent, or
+    /// whether it is a duplicate of a report already sent
+    pub fn check(&self, report: &mut ReceivedReport) -> bool {
+        let fingerprint = fingerprint(report.project.as_deref(), &report.csp_report);
+        report.fingerprint = Some(fingerprint.clone());
+
+        let now = Instant::now();
+        let mut suppressed = self.suppressed.lock().unwrap();
+        match suppressed.get_mut(&fingerprint) {
+            Some(entry) if entry.window_end > now => {
+                entry.count += 1 + report.duplicates;
+                entry.last = Some(report.clone());
+                false
+            }
+            _ => {
+                suppressed.insert(
+                    fingerprint,
+                    Suppressed {
+                        window_end: now + self.window,
+                        last: None,
+                        count: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Remove the violations whose window ended, and return the last
+    /// duplicate of each, with the number of duplicates suppressed
+    pub fn expired(&self) -> Vec<ReceivedReport> {
+        let now = Instant::now();
+        let mut suppressed = self.suppressed.lock().unwrap();
+        let mut reports = vec![];
+
+        suppressed.retain(|_, entry| {
+            if entry.window_end > now {
+                return true;
+            }
+
+            if let Some(mut report) = entry.last.take() {
+                // The last duplicate is sent, and counts the other ones
+                report.duplicates = entry.count - 1;
+                reports.push(report);
+            }
+            false
+        });
+
+        reports
+    }
+}
+
+/// Send the count of the suppressed duplicates at the end of each window
+pub fn spawn_flush(deduplicator: Arc<Deduplicator>, dispatcher: Arc<Dispatcher>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            for report in deduplicator.expired() {
+                debug!(
+                    "Report {} seen {} more times",
+                    report.fingerprint.as_deref().unwrap_or_default(),
+                    report.duplicates + 1
+                );
+
+                if let Err(e) = dispatcher.dispatch(report).await {
+                    error!("{:#}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(blocked_uri: &str, document_uri: &str) -> ReceivedReport {
+        let mut csp_report = CspReportContent::default(
+            blocked_uri,
+            document_uri,
+            "default-src 'self'",
+            "",
+            "script-src 'self'",
+        );
+        csp_report.source_file = Some(String::from("https://shop.example.org/app.js?v=1"));
+        csp_report.line_number = Some(12);
+        ReceivedReport::new(csp_report)
+    }
+
+    fn fingerprint_of(report: &ReceivedReport) -> String {
+        fingerprint(report.project.as_deref(), &report.csp_report)
+    }
+
+    #[test]
+    fn it_normalizes_fingerprinted_fields() {
+        assert_eq!(
+            "https://cdn.example.com",
+            blocked_origin("https://cdn.example.com/a.js?v=2")
+        );
+        assert_eq!("data:", blocked_origin("data:image/png;base64,AAAA"));
+        assert_eq!("inline", blocked_origin("inline"));
+        assert_eq!(
+            "shop.example.org/orders/{id}/items/{id}",
+            document_template(
+                "https://shop.example.org/orders/1234/items/0b9d6f1e-0c3a-4f3e-9a77-5d2b8f6a1c90?x=1"
+            )
+        );
+        assert_eq!(
+            "shop.example.org/blog/cafe",
+            document_template("https://shop.example.org/blog/cafe")
+        );
+
+        assert_eq!(
+            fingerprint_of(&report(
+                "https://cdn.example.com/a.js",
+                "https://shop.example.org/orders/1"
+            )),
+            fingerprint_of(&report(
+                "https://cdn.example.com/b.js",
+                "https://shop.example.org/orders/2"
+            ))
+        );
+        assert_ne!(
+            fingerprint_of(&report(
+                "https://cdn.example.com/a.js",
+                "https://shop.example.org/orders/1"
+            )),
+            fingerprint_of(&report(
+                "https://evil.example.com/a.js",
+                "https://shop.example.org/orders/1"
+            ))
+        );
+    }
+
+    #[test]
+    fn it_suppresses_duplicates_during_the_window() {
+        let deduplicator = Deduplicator::new(Duration::from_millis(50));
+        let document_uri = "https://shop.example.org/cart";
+
+        let mut first = report("inline", document_uri);
+        assert!(deduplicator.check(&mut first));
+        assert!(first.fingerprint.is_some());
+        for _ in 0..3 {
+            assert!(!deduplicator.check(&mut report("inline", document_uri)));
+        }
+        assert!(deduplicator.check(&mut report("eval", document_uri)));
+        assert!(deduplicator.expired().is_empty());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let expired = deduplicator.expired();
+        assert_eq!(1, expired.len());
+        assert_eq!(2, expired[0].duplicates);
+        assert_eq!(first.fingerprint, expired[0].fingerprint);
+
+        // A new window starts with the next report
+        assert!(deduplicator.check(&mut report("inline", document_uri)));
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::{debug, error, info, warn};
+
+use crate::channel::{Channel, Channels, PermanentError};
+use crate::csp::received_report::ReceivedReport;
+use crate::metrics::Metrics;
+use crate::outbox::{queue_name, Entry, Outbox};
+use crate::retry::RetryPolicy;
+
+/// Number of reports waiting to be sent by a channel before new ones are
+/// dropped, without outbox
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Interval between two removals of the outbox segments handled by every
+/// channel
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The in-memory queue of a channel, consumed by its worker
+struct ChannelQueue {
+    name: &'static str,
+    accepts_filtered: bool,
+    sender: Sender<Arc<ReceivedReport>>,
+}
+
+/// Fans out the received reports to the channels, each channel sending its
+/// reports from its own task, so the request handlers never wait for them.
+///
+/// With an outbox, reports are stored on disk and every worker follows the
+/// outbox. Otherwise, they are pushed to in-memory queues, lost on restart.
+pub struct Dispatcher {
+    metrics: Arc<Metrics>,
+    outbox: Option<Arc<Outbox>>,
+
+    /// The channel queues of the global endpoint
+    queues: Vec<ChannelQueue>,
+
+    /// The channel queues of each project
+    project_queues: HashMap<String, Vec<ChannelQueue>>,
+}
+
+impl Dispatcher {
+    fn new(metrics: Arc<Metrics>, outbox: Option<Arc<Outbox>>) -> Self {
+        Dispatcher {
+            metrics,
+            outbox,
+            queues: vec![],
+            project_queues: HashMap::new(),
+        }
+    }
+
+    /// Start the workers of the channels of the global endpoint and of
+    /// every project
+    pub fn start(
+        channels: Channels,
+        metrics: Arc<Metrics>,
+        retry: RetryPolicy,
+        outbox: Option<Arc<Outbox>>,
+    ) -> Result<Self, Error> {
+        let mut dispatcher = Dispatcher::new(metrics, outbox);
+
+        let global_names = channels
+            .global
+            .iter()
+            .map(|channel| queue_name(None, channel.name()));
+        let project_names = channels.projects.iter().flat_map(|(slug, channels)| {
+            channels
+                .iter()
+                .map(move |channel| queue_name(Some(slug), channel.name()))
+        });
+        let queue_names: Vec<String> = global_names.chain(project_names).collect();
+
+        // A new channel only sends the reports received from now on. Its
+        // cursor is created before any report can be received, so none is
+        // missed while its worker starts.
+        if let Some(outbox) = &dispatcher.outbox {
+            for name in &queue_names {
+                if outbox.cursor(name)?.is_none() {
+                    outbox.commit(name, outbox.last_id())?;
+                }
+            }
+        }
+
+        for channel in channels.global {
+            dispatcher
+                .queues
+                .extend(dispatcher.spawn_worker(channel, None, retry));
+        }
+
+        for (slug, project_channels) in channels.projects {
+            let mut queues = vec![];
+
+            for channel in project_channels {
+                queues.extend(dispatcher.spawn_worker(channel, Some(&slug), retry));
+            }
+
+            dispatcher.project_queues.insert(slug, queues);
+        }
+
+        if let Some(outbox) = &dispatcher.outbox {
+            spawn_compaction(outbox.clone(), queue_names);
+        }
+
+        Ok(dispatcher)
+    }
+
+    /// Start the task sending the reports of a channel, and return its queue
+    /// when there is no outbox
+    fn spawn_worker(
+        &self,
+        channel: Arc<dyn Channel>,
+        project: Option<&str>,
+        retry: RetryPolicy,
+    ) -> Option<ChannelQueue> {
+        let name = channel.name();
+        let accepts_filtered = channel.accepts_filtered();
+
+        let worker = Worker {
+            channel,
+            project: project.map(String::from),
+            metrics: self.metrics.clone(),
+            retry,
+        };
+
+        if let Some(outbox) = &self.outbox {
+            tokio::spawn(worker.run_outbox(outbox.clone()));
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(worker.run(receiver));
+
+        Some(ChannelQueue {
+            name,
+            accepts_filtered,
+            sender,
+        })
+    }
+
+    /// Store a report in the outbox, or enqueue it for every channel of its
+    /// project. Filtered reports are only sent to the channels accepting
+    /// them.
+    pub async fn dispatch(&self, report: ReceivedReport) -> Result<(), Error> {
+        if let Some(outbox) = &self.outbox {
+            let id = with_outbox(outbox, move |outbox| outbox.append(report)).await?;
+            debug!("Report {} stored in the outbox", id);
+
+            return Ok(());
+        }
+
+        let queues = match &report.project {
+            Some(project) => self.project_queues.get(project).map(Vec::as_slice),
+            None => Some(self.queues.as_slice()),
+        };
+
+        let report = Arc::new(report);
+
+        for queue in queues.unwrap_or_default() {
+            if report.filtered_by.is_some() && !queue.accepts_filtered {
+                continue;
+            }
+
+            match queue.sender.try_send(report.clone()) {
+                Ok(()) => debug!("Report enqueued for {}", queue.name),
+                Err(TrySendError::Full(_)) => {
+                    warn!("The {} queue is full, dropping the report", queue.name);
+                    self.metrics
+                        .add_channel_drop(report.project.as_deref(), queue.name);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("The {} worker stopped, dropping the report", queue.name);
+                    self.metrics
+                        .add_channel_drop(report.project.as_deref(), queue.name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run a blocking outbox operation outside of the async workers
+async fn with_outbox<T, F>(outbox: &Arc<Outbox>, operation: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Outbox) -> Result<T, Error> + Send + 'static,
+{
+    let outbox = outbox.clone();
+
+    tokio::task::spawn_blocking(move || operation(&outbox)).await?
+}
+
+/// Periodically remove the outbox segments handled by every channel
+fn spawn_compaction(outbox: Arc<Outbox>, queues: Vec<String>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(COMPACTION_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+
+            let queues = queues.clone();
+            match with_outbox(&outbox, move |outbox| outbox.compact(&queues)).await {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} outbox segments", removed),
+                Err(e) => error!("Unable to compact the outbox: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Sends the reports of a channel, one at a time
+struct Worker {
+    channel: Arc<dyn Channel>,
+    project: Option<String>,
+    metrics: Arc<Metrics>,
+    retry: RetryPolicy,
+}
+
+impl Worker {
+    async fn run(self, mut receiver: Receiver<Arc<ReceivedReport>>) {
+        while let Some(report) = receiver.recv().await {
+            let _ = self.send(&report).await;
+        }
+    }
+
+    async fn run_outbox(self, outbox: Arc<Outbox>) {
+        let queue = queue_name(self.project.as_deref(), self.channel.name());
+
+        if let Err(e) = self.follow_outbox(&outbox, &queue).await {
+            error!("The {} worker stopped: {:#}", queue, e);
+        }
+    }
+
+    /// Whether a report of the outbox is for this channel
+    fn wants(&self, report: &ReceivedReport) -> bool {
+        report.project == self.project
+            && (report.filtered_by.is_none() || self.channel.accepts_filtered())
+    }
+
+    /// Send the reports of the outbox after the cursor of the queue, then
+    /// the ones appended to it, moving the given up ones to the dead letters
+    async fn follow_outbox(&self, outbox: &Arc<Outbox>, queue: &str) -> Result<(), Error> {
+        let mut appended = outbox.subscribe();
+
+        // Created when the dispatcher starts
+        let mut cursor = outbox
+            .cursor(queue)?
+            .ok_or_else(|| anyhow!("The cursor of {} is missing", queue))?;
+
+        let mut reader = Some(outbox.reader(cursor));
+        let mut last_read = cursor;
+
+        loop {
+            appended.borrow_and_update();
+
+            loop {
+                let mut current = reader.take().unwrap();
+                let (current, entry) = tokio::task::spawn_blocking(move || {
+                    let entry = current.next();
+                    (current, entry)
+                })
+                .await?;
+                reader = Some(current);
+
+                let entry: Entry = match entry? {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                last_read = entry.id;
+
+                if !self.wants(&entry.report) {
+                    continue;
+                }
+
+                let result = self.send(&entry.report).await;
+                let name = queue.to_string();
+                with_outbox(outbox, move |outbox| {
+                    if let Err(e) = result {
+                        outbox.dead_letter(&name, &entry, &e)?;
+                    }
+                    outbox.commit(&name, entry.id)
+                })
+                .await?;
+                cursor = last_read;
+            }
+
+            // Skip the reports of the other channels
+            if last_read > cursor {
+                let name = queue.to_string();
+                with_outbox(outbox, move |outbox| outbox.commit(&name, last_read)).await?;
+                cursor = last_read;
+            }
+
+            if appended.changed().await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send a report, retrying with backoff until it is sent, the retries
+    /// are exhausted or the error is permanent. Returns the last error when
+    /// the report is given up.
+    async fn send(&self, report: &ReceivedReport) -> Result<(), Error> {
+        let name = self.channel.name();
+        let project = self.project.as_deref();
+        let mut retry = 0;
+
+        loop {
+            let e = match self.channel.send_report(report).await {
+                Ok(()) => {
+                    debug!("Report sent to {}", name);
+                    self.metrics.add_channel_sent(project, name);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            self.metrics.add_channel_error(project, name);
+
+            if e.is::<PermanentError>() {
+                error!("Unable to send report to {}, giving up: {:#}", name, e);
+                self.metrics.add_channel_failure(project, name);
+                return Err(e);
+            }
+
+            if retry >= self.retry.retries {
+                error!(
+                    "Unable to send report to {}, giving up after {} attempts: {:#}",
+                    name,
+                    retry + 1,
+                    e
+                );
+                self.metrics.add_channel_failure(project, name);
+                return Err(e);
+            }
+
+            retry += 1;
+            let delay = self.retry.delay(retry);
+            warn!(
+                "Unable to send report to {}, retry {}/{} in {:?}: {:#}",
+                name, retry, self.retry.retries, delay, e
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+    use crate::csp::rules::FilterRules;
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+    struct TestChannel {
+        accepts_filtered: bool,
+        sent: UnboundedSender<String>,
+
+        /// Number of attempts failing before the report is sent
+        failures: AtomicU32,
+        permanent: bool,
+    }
+
+    impl TestChannel {
+        fn new(sent: UnboundedSender<String>) -> Self {
+            TestChannel {
+                accepts_filtered: false,
+                sent,
+                failures: AtomicU32::new(0),
+                permanent: false,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Channel for TestChannel {
+        fn load_from_env() -> Result<Self, Error> {
+            Err(anyhow!("not loaded from the environment"))
+        }
+
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn accepts_filtered(&self) -> bool {
+            self.accepts_filtered
+        }
+
+        async fn send_report(&self, report: &ReceivedReport) -> Result<(), Error> {
+            if self.permanent {
+                return Err(PermanentError(anyhow!("rejected")).into());
+            }
+
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(anyhow!("unavailable"));
+            }
+
+            self.sent.send(report.csp_report.blocked_uri.clone())?;
+            Ok(())
+        }
+    }
+
+    fn report(blocked_uri: &str) -> ReceivedReport {
+        ReceivedReport::new(CspReportContent::default(blocked_uri, "", "", "", ""))
+    }
+
+    fn start(
+        channels: Vec<Arc<dyn Channel>>,
+        metrics: Arc<Metrics>,
+        retry: RetryPolicy,
+        outbox: Option<Arc<Outbox>>,
+    ) -> Dispatcher {
+        let channels = Channels {
+            global: channels,
+            projects: vec![],
+        };
+
+        Dispatcher::start(channels, metrics, retry, outbox).unwrap()
+    }
+
+    fn no_delay(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    async fn next(received: &mut UnboundedReceiver<String>) -> String {
+        tokio::time::timeout(Duration::from_secs(1), received.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_sends_reports_to_every_channel() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let mut filtered_channel = TestChannel::new(sent.clone());
+        filtered_channel.accepts_filtered = true;
+        let channels: Vec<Arc<dyn Channel>> =
+            vec![Arc::new(TestChannel::new(sent)), Arc::new(filtered_channel)];
+        let dispatcher = start(channels, Arc::default(), no_delay(0), None);
+
+        dispatcher
+            .dispatch(report("https://a.example.com"))
+            .await
+            .unwrap();
+
+        let mut filtered = report("https://b.example.com");
+        filtered.filtered_by = Some(String::from("blocked_uri"));
+        dispatcher.dispatch(filtered).await.unwrap();
+
+        let mut sent = vec![];
+        for _ in 0..3 {
+            sent.push(next(&mut received).await);
+        }
+        sent.sort();
+
+        assert_eq!(
+            vec![
+                "https://a.example.com",
+                "https://a.example.com",
+                "https://b.example.com"
+            ],
+            sent
+        );
+    }
+
+    #[tokio::test]
+    async fn it_retries_failed_reports() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let channel = TestChannel::new(sent);
+        channel.failures.store(2, Ordering::SeqCst);
+        let metrics = Arc::new(Metrics::default());
+        let dispatcher = start(vec![Arc::new(channel)], metrics.clone(), no_delay(2), None);
+
+        dispatcher
+            .dispatch(report("https://a.example.com"))
+            .await
+            .unwrap();
+        assert_eq!("https://a.example.com", next(&mut received).await);
+
+        let rules = FilterRules::empty();
+        let stats = metrics.stats(None, &rules);
+        assert_eq!(1, stats.channels[0].sent);
+        assert_eq!(2, stats.channels[0].errors);
+        assert_eq!(0, stats.channels[0].failed);
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_on_permanent_errors() {
+        let (sent, _received) = mpsc::unbounded_channel();
+        let mut channel = TestChannel::new(sent);
+        channel.permanent = true;
+        let metrics = Arc::new(Metrics::default());
+        let dispatcher = start(vec![Arc::new(channel)], metrics.clone(), no_delay(5), None);
+
+        dispatcher
+            .dispatch(report("https://a.example.com"))
+            .await
+            .unwrap();
+
+        let rules = FilterRules::empty();
+        for _ in 0..100 {
+            let stats = metrics.stats(None, &rules);
+            if stats.channels.first().map(|stats| stats.failed) == Some(1) {
+                assert_eq!(1, stats.channels[0].errors);
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("The report was not given up");
+    }
+
+    #[tokio::test]
+    async fn it_sends_reports_through_the_outbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Arc::new(Outbox::open(dir.path()).unwrap());
+
+        // Stored before the new channel is added, so not sent
+        outbox.append(report("https://old.example.com")).unwrap();
+
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let mut channel = TestChannel::new(sent.clone());
+        channel.permanent = true;
+        let channels: Vec<Arc<dyn Channel>> = vec![Arc::new(channel)];
+        let dispatcher = start(channels, Arc::default(), no_delay(0), Some(outbox.clone()));
+
+        // Received right away, before the worker had a chance to run
+        dispatcher
+            .dispatch(report("https://a.example.com"))
+            .await
+            .unwrap();
+
+        for _ in 0..100 {
+            if outbox.cursor("test").unwrap() == Some(2) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let dead_letters = crate::outbox::dead_letters(dir.path(), "test").unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!(
+            "https://a.example.com",
+            dead_letters[0].report.csp_report.blocked_uri
+        );
+
+        // After a restart, the worker resumes after the handled reports
+        let channels: Vec<Arc<dyn Channel>> = vec![Arc::new(TestChannel::new(sent))];
+        let dispatcher = start(channels, Arc::default(), no_delay(0), Some(outbox.clone()));
+        dispatcher
+            .dispatch(report("https://b.example.com"))
+            .await
+            .unwrap();
+        assert_eq!("https://b.example.com", next(&mut received).await);
+    }
+}
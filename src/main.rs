@@ -1,32 +1,54 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 mod args;
 mod channel;
+mod config;
 mod csp;
+mod dedup;
+mod dispatcher;
+mod metrics;
+mod outbox;
+mod project;
+mod reload;
+mod retry;
 
-use axum::http::{HeaderValue, Method};
-use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
-
-#[cfg(feature = "mail")]
-use channel::mailer::Mailer;
-
-#[cfg(feature = "sentry")]
-use channel::sentry::Sentry;
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::http::header::{CONTENT_TYPE, USER_AGENT};
+use axum::http::{HeaderMap, HeaderValue, Method};
+use axum::response::Response;
+use axum::{
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
 
 extern crate dotenv;
 use dotenv::dotenv;
 
-use crate::csp::csp_report::CspReport;
-
-#[cfg(any(feature = "mail", feature = "sentry"))]
-use crate::channel::Channel;
+use crate::csp::csp_report::{CspReportPayload, ReportFormat};
+use crate::csp::received_report::ReceivedReport;
+use crate::csp::rules::FilterRules;
 
 use tower_http::cors::CorsLayer;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
+use crate::channel::Channels;
+use crate::dedup::Deduplicator;
+use crate::dispatcher::Dispatcher;
+use crate::metrics::Metrics;
+use crate::outbox::Outbox;
+use crate::project::{Project, Projects};
+use crate::reload::{spawn_reloader, SharedFilterRules};
+use crate::retry::RetryPolicy;
+use anyhow::Error;
+use arc_swap::ArcSwap;
 use clap::Parser;
 use tracing::Level;
-use tracing::log::debug;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
 #[tokio::main]
@@ -49,15 +71,108 @@ async fn main() {
     let subscriber = FmtSubscriber::builder().with_max_level(max_level).finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
+    // Commands
+    if let Some(Command::Outbox(command)) = &args.command {
+        let dir = args.outbox.as_deref().unwrap_or_else(|| {
+            error!("The outbox directory must be set with --outbox or MORBO_OUTBOX");
+            std::process::exit(1);
+        });
+
+        if let Err(e) = outbox::inspect(dir, command) {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    // Filters
+    let filter_rules = match &args.filters {
+        Some(path) => FilterRules::load(path).unwrap_or_else(|e| {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }),
+        None => FilterRules::default(),
+    };
+    let filter_rules: SharedFilterRules = Arc::new(ArcSwap::from_pointee(filter_rules));
+
+    // Projects
+    let projects = match &args.projects {
+        Some(path) => Projects::load(path, &filter_rules).unwrap_or_else(|e| {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }),
+        None => Projects::default(),
+    };
+
+    let interval = Duration::from_secs(args.filters_watch_interval.max(1));
+
+    if let Some(path) = args.filters {
+        spawn_reloader(path, filter_rules.clone(), interval);
+    }
+
+    for project in projects.iter() {
+        if let Some(path) = &project.filters {
+            spawn_reloader(path.clone(), project.filter_rules.clone(), interval);
+        }
+    }
+
+    // Channels
+    let metrics = Arc::new(Metrics::default());
+    let retry = RetryPolicy {
+        retries: args.retries,
+        initial_delay: Duration::from_millis(args.retry_delay),
+        max_delay: Duration::from_secs(args.retry_max_delay),
+    };
+    let outbox = args.outbox.as_deref().map(|dir| {
+        Arc::new(Outbox::open(dir).unwrap_or_else(|e| {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }))
+    });
+    let channels = Channels::load(&projects).unwrap_or_else(|e| {
+        error!("{:#}", e);
+        std::process::exit(1);
+    });
+
+    if args.check_channels {
+        if let Err(e) = channels.check().await {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+        info!("Every channel is reachable");
+    }
+
+    let dispatcher =
+        Dispatcher::start(channels, metrics.clone(), retry, outbox).unwrap_or_else(|e| {
+            error!("Unable to start the channel workers: {:#}", e);
+            std::process::exit(1);
+        });
+    let dispatcher = Arc::new(dispatcher);
+
+    let deduplicator = args.dedup_window.map(|window| {
+        let deduplicator = Arc::new(Deduplicator::new(Duration::from_secs(window)));
+        dedup::spawn_flush(deduplicator.clone(), dispatcher.clone());
+        deduplicator
+    });
+
     // App initialisation
     let app = Router::new()
         .route("/_/csp-reports", post(csp_report_action))
+        .route("/_/csp-reports/:project", post(project_csp_report_action))
+        .route("/_/stats", get(stats_action))
+        .route("/_/stats/:project", get(project_stats_action))
         .layer(
             CorsLayer::new()
                 .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
                 .allow_origin("*".parse::<HeaderValue>().unwrap())
                 .allow_credentials(false),
-        );
+        )
+        .layer(Extension(metrics))
+        .layer(Extension(dispatcher))
+        .layer(Extension(deduplicator))
+        .layer(Extension(filter_rules))
+        .layer(Extension(Arc::new(projects)));
 
     // Run it
     let port: u16 = args.port;
@@ -69,24 +184,168 @@ async fn main() {
         .unwrap();
 }
 
-async fn csp_report_action(Json(payload): Json<CspReport>) -> impl IntoResponse {
-    let csp_report = payload.csp_report;
-
-    if !csp_report.is_in_block_list() {
-        #[cfg(feature = "mail")]
-        {
-            debug!("Sending report by email");
-            let mailer = Mailer::load_from_env();
-            let _res = mailer.send_report(&csp_report).unwrap();
-        };
-
-        #[cfg(feature = "sentry")]
-        {
-            debug!("Sending report to sentry");
-            let sentry = Sentry::load_from_env();
-            let _res = sentry.send_report(&csp_report).unwrap();
-        };
+async fn csp_report_action(
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(dispatcher): Extension<Arc<Dispatcher>>,
+    Extension(deduplicator): Extension<Option<Arc<Deduplicator>>>,
+    Extension(filter_rules): Extension<SharedFilterRules>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let filter_rules = filter_rules.load_full();
+    receive_reports(
+        &headers,
+        &body,
+        &filter_rules,
+        &metrics,
+        &dispatcher,
+        deduplicator.as_deref(),
+        None,
+    )
+    .await
+}
+
+async fn project_csp_report_action(
+    Path(slug): Path<String>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(dispatcher): Extension<Arc<Dispatcher>>,
+    Extension(deduplicator): Extension<Option<Arc<Deduplicator>>>,
+    Extension(projects): Extension<Arc<Projects>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let project = match projects.get(&slug) {
+        Some(project) if project.enabled => project,
+        Some(_) => return (StatusCode::NOT_FOUND, "Disabled project").into_response(),
+        None => return (StatusCode::NOT_FOUND, "Unknown project").into_response(),
+    };
+
+    let filter_rules = project.filter_rules.load_full();
+    receive_reports(
+        &headers,
+        &body,
+        &filter_rules,
+        &metrics,
+        &dispatcher,
+        deduplicator.as_deref(),
+        Some(project),
+    )
+    .await
+}
+
+/// Parse a request body according to its content type, and enqueue its
+/// reports. The channels send them in the background.
+async fn receive_reports(
+    headers: &HeaderMap,
+    body: &[u8],
+    filter_rules: &FilterRules,
+    metrics: &Metrics,
+    dispatcher: &Dispatcher,
+    deduplicator: Option<&Deduplicator>,
+    project: Option<&Project>,
+) -> Response {
+    let format = match headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ReportFormat::from_content_type)
+    {
+        Some(format) => format,
+        None => {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected application/csp-report, application/json or application/reports+json",
+            )
+                .into_response();
+        }
+    };
+
+    let payload = match CspReportPayload::parse(format, body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            let count = metrics.add_malformed_report();
+            warn!("Malformed {:?} report ({} so far): {}", format, count, e);
+            return (StatusCode::BAD_REQUEST, format!("Malformed report: {}", e)).into_response();
+        }
+    };
+
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+
+    for csp_report in payload.into_csp_report_contents() {
+        let mut report = ReceivedReport::new(csp_report);
+        report.user_agent = user_agent.map(String::from);
+        report.project = project.map(|project| project.slug.clone());
+
+        if let Err(e) = send_report(report, filter_rules, metrics, dispatcher, deduplicator).await {
+            error!("{:#}", e);
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Unable to store the report",
+            )
+                .into_response();
+        }
     }
 
-    (StatusCode::OK, Json(""))
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Send a report to every enabled channel, unless it is in the block list.
+/// Filtered reports are only sent to the channels accepting them, and
+/// duplicates are only counted, when they are suppressed.
+async fn send_report(
+    mut report: ReceivedReport,
+    filter_rules: &FilterRules,
+    metrics: &Metrics,
+    dispatcher: &Dispatcher,
+    deduplicator: Option<&Deduplicator>,
+) -> Result<(), Error> {
+    let slug = report.project.as_deref();
+    debug!(
+        "Received report for project {}\n{}",
+        slug.unwrap_or("-"),
+        report.csp_report.summary()
+    );
+
+    if let Some(filter) = report.csp_report.find_filter(filter_rules) {
+        debug!("Report dropped by {}", filter);
+        metrics.add_filter_hit(slug, &filter);
+        report.filtered_by = Some(filter.rule.to_string());
+    }
+
+    if let Some(deduplicator) = deduplicator {
+        if !deduplicator.check(&mut report) {
+            debug!("Duplicate report suppressed");
+            return Ok(());
+        }
+    }
+
+    dispatcher.dispatch(report).await
+}
+
+/// Expose the number of malformed reports, and of reports dropped by each filter
+async fn stats_action(
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(filter_rules): Extension<SharedFilterRules>,
+) -> impl IntoResponse {
+    let filter_rules = filter_rules.load();
+
+    Json(serde_json::to_value(metrics.stats(None, &filter_rules)).unwrap())
+}
+
+/// Expose the statistics of a project
+async fn project_stats_action(
+    Path(slug): Path<String>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(projects): Extension<Arc<Projects>>,
+) -> Response {
+    let project = match projects.get(&slug) {
+        Some(project) => project,
+        None => return (StatusCode::NOT_FOUND, "Unknown project").into_response(),
+    };
+
+    let filter_rules = project.filter_rules.load();
+    let stats = metrics.stats(Some(&project.slug), &filter_rules);
+
+    Json(serde_json::to_value(stats).unwrap()).into_response()
 }
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::csp::condition::Field;
+use crate::csp::matcher::MatchMode;
+use crate::csp::rules::{FilterMatch, FilterRules};
+
+/// Identifies a filter list entry or a rule of a project across reloads
+type FilterKey = (Option<String>, String, Option<String>);
+
+fn filter_key(project: Option<&str>, filter: &FilterMatch) -> FilterKey {
+    (
+        project.map(String::from),
+        filter.rule.to_string(),
+        filter.matcher.map(|matcher| matcher.pattern.clone()),
+    )
+}
+
+/// Identifies a channel of a project
+type ChannelKey = (Option<String>, &'static str);
+
+/// Delivery counters of a channel
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChannelStats {
+    pub channel: &'static str,
+
+    /// Reports sent
+    pub sent: u64,
+
+    /// Failed attempts to send a report, including the retried ones
+    pub errors: u64,
+
+    /// Reports given up after the last retry, or on a permanent error
+    pub failed: u64,
+
+    /// Reports dropped because the queue of the channel was full
+    pub dropped: u64,
+}
+
+/// Counters shared by the request handlers and the channel workers
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Number of request bodies that could not be parsed as a report
+    malformed_reports: AtomicU64,
+
+    /// Number of reports dropped by each filter list entry and rule
+    filter_hits: Mutex<HashMap<FilterKey, u64>>,
+
+    /// Delivery counters of each channel
+    channels: Mutex<HashMap<ChannelKey, ChannelStats>>,
+}
+
+/// The statistics of the server, as exposed by the stats endpoint
+#[derive(Debug, Serialize)]
+pub struct Stats<'a> {
+    pub malformed_reports: u64,
+    pub filters: Vec<FilterStats<'a>>,
+    pub channels: Vec<ChannelStats>,
+}
+
+/// The number of reports dropped by a filter list entry or a rule
+#[derive(Debug, Serialize)]
+pub struct FilterStats<'a> {
+    pub rule: &'a str,
+    pub field: Option<Field>,
+    pub pattern: Option<&'a str>,
+    pub mode: Option<MatchMode>,
+    pub hits: u64,
+}
+
+impl Metrics {
+    /// Count a malformed body, and return the total count so far
+    pub fn add_malformed_report(&self) -> u64 {
+        self.malformed_reports.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a report dropped by a filter, for the given project (or for
+    /// the global endpoint)
+    pub fn add_filter_hit(&self, project: Option<&str>, filter: &FilterMatch) {
+        let mut filter_hits = self.filter_hits.lock().unwrap();
+        *filter_hits.entry(filter_key(project, filter)).or_default() += 1;
+    }
+
+    fn update_channel(
+        &self,
+        project: Option<&str>,
+        channel: &'static str,
+        update: impl FnOnce(&mut ChannelStats),
+    ) {
+        let mut channels = self.channels.lock().unwrap();
+        let stats = channels
+            .entry((project.map(String::from), channel))
+            .or_insert_with(|| ChannelStats {
+                channel,
+                ..Default::default()
+            });
+
+        update(stats);
+    }
+
+    pub fn add_channel_sent(&self, project: Option<&str>, channel: &'static str) {
+        self.update_channel(project, channel, |stats| stats.sent += 1);
+    }
+
+    pub fn add_channel_error(&self, project: Option<&str>, channel: &'static str) {
+        self.update_channel(project, channel, |stats| stats.errors += 1);
+    }
+
+    pub fn add_channel_failure(&self, project: Option<&str>, channel: &'static str) {
+        self.update_channel(project, channel, |stats| stats.failed += 1);
+    }
+
+    pub fn add_channel_drop(&self, project: Option<&str>, channel: &'static str) {
+        self.update_channel(project, channel, |stats| stats.dropped += 1);
+    }
+
+    /// The statistics of a project (or of the global endpoint), including
+    /// every filter of its current rules, even the ones which never matched
+    pub fn stats<'a>(&self, project: Option<&str>, rules: &'a FilterRules) -> Stats<'a> {
+        let filter_hits = self.filter_hits.lock().unwrap();
+
+        let filters = rules
+            .entries()
+            .into_iter()
+            .map(|filter| FilterStats {
+                rule: filter.rule,
+                field: filter.field,
+                pattern: filter.matcher.map(|matcher| matcher.pattern.as_str()),
+                mode: filter.matcher.map(|matcher| matcher.mode),
+                hits: filter_hits
+                    .get(&filter_key(project, &filter))
+                    .copied()
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        let mut channels: Vec<ChannelStats> = self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((channel_project, _), _)| channel_project.as_deref() == project)
+            .map(|(_, stats)| stats.clone())
+            .collect();
+        channels.sort_by_key(|stats| stats.channel);
+
+        Stats {
+            malformed_reports: self.malformed_reports.load(Ordering::Relaxed),
+            filters,
+            channels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    #[test]
+    fn it_counts_filter_hits() {
+        let metrics = Metrics::default();
+        let rules = FilterRules::default();
+
+        let csp_c = CspReportContent::default("mxjscall://", "", "", "", "");
+        let filter = csp_c.find_filter(&rules).unwrap();
+        metrics.add_filter_hit(None, &filter);
+        metrics.add_filter_hit(None, &filter);
+        metrics.add_filter_hit(Some("checkout"), &filter);
+
+        let stats = metrics.stats(None, &rules);
+        assert_eq!(rules.entries().len(), stats.filters.len());
+
+        let hits: Vec<(&str, u64)> = stats
+            .filters
+            .iter()
+            .filter(|filter| filter.hits > 0)
+            .map(|filter| (filter.pattern.unwrap(), filter.hits))
+            .collect();
+        assert_eq!(vec![("mxjscall://", 2)], hits);
+    }
+
+    #[test]
+    fn it_counts_channel_deliveries_per_project() {
+        let metrics = Metrics::default();
+        let rules = FilterRules::empty();
+
+        metrics.add_channel_sent(None, "mail");
+        metrics.add_channel_error(None, "mail");
+        metrics.add_channel_error(None, "mail");
+        metrics.add_channel_failure(None, "mail");
+        metrics.add_channel_sent(Some("checkout"), "mail");
+
+        let stats = metrics.stats(None, &rules);
+        assert_eq!(1, stats.channels.len());
+        assert_eq!("mail", stats.channels[0].channel);
+        assert_eq!(1, stats.channels[0].sent);
+        assert_eq!(2, stats.channels[0].errors);
+        assert_eq!(1, stats.channels[0].failed);
+
+        let stats = metrics.stats(Some("checkout"), &rules);
+        assert_eq!(1, stats.channels[0].sent);
+        assert_eq!(0, stats.channels[0].errors);
+    }
+}
@@ -0,0 +1,631 @@
+//! Durable queue of the received reports, between the request handlers and
+//! the channels.
+//!
+//! Reports are appended to JSON lines segment files, synced to disk before
+//! the browser gets its answer. Each channel reads the segments on its own,
+//! and stores the id of the last report it handled in a cursor file, so
+//! pending reports are sent again after a restart. Reports given up by a
+//! channel are moved to its dead letters file.
+//!
+//! ```text
+//! <dir>/segments/00000000000000000001.jsonl  reports, named by their first id
+//! <dir>/cursors/<queue>                      last id handled by a channel
+//! <dir>/dead-letters/<queue>.jsonl           reports given up by a channel
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::args::OutboxCommand;
+use crate::csp::received_report::ReceivedReport;
+
+/// Size from which a new segment is started
+const SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// A report in the outbox
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub report: ReceivedReport,
+}
+
+/// A report given up by a channel
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub failed_at: DateTime<Utc>,
+    pub error: String,
+    pub report: ReceivedReport,
+}
+
+/// Only the id of an entry, to find the last id without parsing reports
+#[derive(Deserialize)]
+struct EntryId {
+    id: u64,
+}
+
+/// The name of the queue of a channel, used for its cursor and dead letters
+pub fn queue_name(project: Option<&str>, channel: &str) -> String {
+    match project {
+        Some(project) => format!("{}.{}", project, channel),
+        None => String::from(channel),
+    }
+}
+
+fn segments_dir(dir: &Path) -> PathBuf {
+    dir.join("segments")
+}
+
+fn cursors_dir(dir: &Path) -> PathBuf {
+    dir.join("cursors")
+}
+
+fn dead_letters_dir(dir: &Path) -> PathBuf {
+    dir.join("dead-letters")
+}
+
+fn segment_path(dir: &Path, first_id: u64) -> PathBuf {
+    segments_dir(dir).join(format!("{:020}.jsonl", first_id))
+}
+
+/// The first ids of the segments, in order
+fn segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut segments = vec![];
+
+    for file in fs::read_dir(segments_dir(dir))? {
+        let path = file?.path();
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl")
+        {
+            if let Some(first_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                segments.push(first_id);
+            }
+        }
+    }
+
+    segments.sort_unstable();
+
+    Ok(segments)
+}
+
+/// Sync a directory, so the files created in it survive a crash
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Append a line to a file, and sync it to disk
+fn append_line(path: &Path, line: &[u8]) -> Result<(), Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Truncate a segment after its last complete line, as a crash may have
+/// left a partially written one. Returns the size of the segment and the id
+/// of its last entry.
+fn recover_segment(path: &Path) -> Result<(u64, Option<u64>), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = vec![];
+    let mut size = 0;
+    let mut last_id = None;
+
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+
+        if read == 0 {
+            break;
+        }
+
+        if !line.ends_with(b"\n") {
+            warn!(
+                "Dropping a partially written entry at the end of {}",
+                path.display()
+            );
+            OpenOptions::new().write(true).open(path)?.set_len(size)?;
+            break;
+        }
+
+        size += read as u64;
+
+        if let Ok(entry) = serde_json::from_slice::<EntryId>(&line) {
+            last_id = Some(entry.id);
+        }
+    }
+
+    Ok((size, last_id))
+}
+
+/// The id of the last report appended to the outbox
+pub fn last_id(dir: &Path) -> Result<u64, Error> {
+    let segments = segments(dir)?;
+
+    let last_id = match segments.last() {
+        Some(&first_id) => {
+            let reader = BufReader::new(File::open(segment_path(dir, first_id))?);
+
+            reader
+                .split(b'\n')
+                .filter_map(|line| serde_json::from_slice::<EntryId>(&line.ok()?).ok())
+                .last()
+                .map_or(first_id - 1, |entry| entry.id)
+        }
+        None => 0,
+    };
+
+    Ok(last_id)
+}
+
+/// The queues which have a cursor
+pub fn queues(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut queues: Vec<String> = fs::read_dir(cursors_dir(dir))?
+        .filter_map(|file| file.ok()?.file_name().into_string().ok())
+        .filter(|name| !name.ends_with(".tmp"))
+        .collect();
+    queues.sort();
+
+    Ok(queues)
+}
+
+/// The id of the last report handled by a queue, if it ever ran
+pub fn cursor(dir: &Path, queue: &str) -> Result<Option<u64>, Error> {
+    let path = cursors_dir(dir).join(queue);
+
+    match fs::read_to_string(&path) {
+        Ok(content) => {
+            Ok(Some(content.trim().parse().with_context(|| {
+                format!("Invalid cursor {}", path.display())
+            })?))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The reports given up by a queue
+pub fn dead_letters(dir: &Path, queue: &str) -> Result<Vec<DeadLetter>, Error> {
+    let path = dead_letters_dir(dir).join(format!("{}.jsonl", queue));
+
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    BufReader::new(file)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The queues which have dead letters
+pub fn dead_letter_queues(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut queues: Vec<String> = fs::read_dir(dead_letters_dir(dir))?
+        .filter_map(|file| {
+            let name = file.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".jsonl").map(String::from)
+        })
+        .collect();
+    queues.sort();
+
+    Ok(queues)
+}
+
+/// Run an outbox command of the CLI
+pub fn inspect(dir: &Path, command: &OutboxCommand) -> Result<(), Error> {
+    if !dir.is_dir() {
+        bail!("No outbox in {}", dir.display());
+    }
+
+    let mut out = io::stdout().lock();
+
+    match command {
+        OutboxCommand::Status => {
+            let last_id = last_id(dir)?;
+            writeln!(out, "Last report: {}", last_id)?;
+
+            for queue in queues(dir)? {
+                let cursor = cursor(dir, &queue)?.unwrap_or(0);
+                writeln!(
+                    out,
+                    "{}: {} ({} behind), {} dead letters",
+                    queue,
+                    cursor,
+                    last_id.saturating_sub(cursor),
+                    dead_letters(dir, &queue)?.len()
+                )?;
+            }
+        }
+        OutboxCommand::DeadLetters { queue } => {
+            let queues = match queue {
+                Some(queue) => vec![queue.clone()],
+                None => dead_letter_queues(dir)?,
+            };
+
+            for queue in queues {
+                for dead_letter in dead_letters(dir, &queue)? {
+                    writeln!(out, "{}", serde_json::to_string(&dead_letter)?)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The segment being written
+struct Writer {
+    file: File,
+    size: u64,
+    next_id: u64,
+}
+
+/// The outbox of a server
+pub struct Outbox {
+    dir: PathBuf,
+    segment_size: u64,
+    writer: Mutex<Writer>,
+
+    /// The id of the last appended report, watched by the readers
+    appended: watch::Sender<u64>,
+    appended_receiver: watch::Receiver<u64>,
+}
+
+impl Outbox {
+    /// Open an outbox, creating its directories if needed
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        for dir in [segments_dir(dir), cursors_dir(dir), dead_letters_dir(dir)] {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Unable to create {}", dir.display()))?;
+        }
+
+        let writer = match segments(dir)?.last() {
+            Some(&first_id) => {
+                let path = segment_path(dir, first_id);
+                let (size, last_id) = recover_segment(&path)
+                    .with_context(|| format!("Unable to read {}", path.display()))?;
+
+                Writer {
+                    file: OpenOptions::new().append(true).open(&path)?,
+                    size,
+                    next_id: last_id.map_or(first_id, |id| id + 1),
+                }
+            }
+            None => Writer {
+                file: create_segment(dir, 1)?,
+                size: 0,
+                next_id: 1,
+            },
+        };
+
+        let (appended, appended_receiver) = watch::channel(writer.next_id - 1);
+
+        Ok(Outbox {
+            dir: dir.to_path_buf(),
+            segment_size: SEGMENT_SIZE,
+            writer: Mutex::new(writer),
+            appended,
+            appended_receiver,
+        })
+    }
+
+    /// Append a report, and return its id once it is synced to disk
+    pub fn append(&self, report: ReceivedReport) -> Result<u64, Error> {
+        let mut writer = self.writer.lock().unwrap();
+
+        if writer.size >= self.segment_size {
+            writer.file = create_segment(&self.dir, writer.next_id)?;
+            writer.size = 0;
+        }
+
+        let entry = Entry {
+            id: writer.next_id,
+            report,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        if let Err(e) = writer
+            .file
+            .write_all(&line)
+            .and_then(|_| writer.file.sync_data())
+        {
+            // Do not leave a partial line before the next entries
+            let _ = writer.file.set_len(writer.size);
+            return Err(Error::new(e).context("Unable to write to the outbox"));
+        }
+
+        writer.size += line.len() as u64;
+        writer.next_id += 1;
+        let _ = self.appended.send(entry.id);
+
+        Ok(entry.id)
+    }
+
+    /// Watch the id of the last appended report
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.appended_receiver.clone()
+    }
+
+    pub fn last_id(&self) -> u64 {
+        self.writer.lock().unwrap().next_id - 1
+    }
+
+    /// Read the reports appended after the given id
+    pub fn reader(&self, after: u64) -> Reader {
+        Reader {
+            dir: self.dir.clone(),
+            after,
+            segment: None,
+            file: None,
+        }
+    }
+
+    pub fn cursor(&self, queue: &str) -> Result<Option<u64>, Error> {
+        cursor(&self.dir, queue)
+    }
+
+    /// Store the id of the last report handled by a queue
+    pub fn commit(&self, queue: &str, id: u64) -> Result<(), Error> {
+        let dir = cursors_dir(&self.dir);
+        let tmp_path = dir.join(format!("{}.tmp", queue));
+
+        let mut file = File::create(&tmp_path)?;
+        write!(file, "{}", id)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(queue))?;
+
+        Ok(())
+    }
+
+    /// Move a report given up by a queue to its dead letters
+    pub fn dead_letter(&self, queue: &str, entry: &Entry, error: &Error) -> Result<(), Error> {
+        let dead_letter = DeadLetter {
+            id: entry.id,
+            failed_at: Utc::now(),
+            error: format!("{:#}", error),
+            report: entry.report.clone(),
+        };
+        let mut line = serde_json::to_vec(&dead_letter)?;
+        line.push(b'\n');
+
+        append_line(
+            &dead_letters_dir(&self.dir).join(format!("{}.jsonl", queue)),
+            &line,
+        )
+    }
+
+    /// Remove the segments whose reports were handled by every queue, and
+    /// return how many were removed
+    pub fn compact(&self, queues: &[String]) -> Result<usize, Error> {
+        let mut handled = u64::MAX;
+
+        for queue in queues {
+            handled = handled.min(self.cursor(queue)?.unwrap_or(0));
+        }
+
+        let segments = segments(&self.dir)?;
+        let mut removed = 0;
+
+        // The last segment is the one being written, and is always kept
+        for pair in segments.windows(2) {
+            if pair[1] - 1 > handled {
+                break;
+            }
+
+            fs::remove_file(segment_path(&self.dir, pair[0]))?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+fn create_segment(dir: &Path, first_id: u64) -> Result<File, Error> {
+    let path = segment_path(dir, first_id);
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Unable to create {}", path.display()))?;
+    sync_dir(&segments_dir(dir))?;
+
+    Ok(file)
+}
+
+/// Reads the reports of an outbox in order, following the appended ones
+pub struct Reader {
+    dir: PathBuf,
+
+    /// The id of the last report read
+    after: u64,
+
+    segment: Option<u64>,
+    file: Option<BufReader<File>>,
+}
+
+impl Reader {
+    fn open_segment(&mut self, first_id: u64) -> Result<(), Error> {
+        self.file = Some(BufReader::new(File::open(segment_path(
+            &self.dir, first_id,
+        ))?));
+        self.segment = Some(first_id);
+
+        Ok(())
+    }
+
+    /// Read a complete line of the current segment, if any
+    fn read_line(&mut self) -> Result<Option<String>, Error> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let offset = file.stream_position()?;
+        let mut line = String::new();
+        file.read_line(&mut line)?;
+
+        if !line.ends_with('\n') {
+            // Wait for the rest of a line being written
+            file.seek(SeekFrom::Start(offset))?;
+            return Ok(None);
+        }
+
+        Ok(Some(line))
+    }
+
+    /// The next report, or `None` when every appended report was read
+    pub fn next(&mut self) -> Result<Option<Entry>, Error> {
+        loop {
+            let line = match self.read_line()? {
+                Some(line) => line,
+                None => {
+                    let segments = segments(&self.dir)?;
+                    let next_segment = match self.segment {
+                        Some(current) => segments.into_iter().find(|&first| first > current),
+                        // The segment which contains the next report
+                        None => segments
+                            .iter()
+                            .rev()
+                            .find(|&&first| first <= self.after + 1)
+                            .or_else(|| segments.first())
+                            .copied(),
+                    };
+
+                    let next_segment = match next_segment {
+                        Some(next_segment) => next_segment,
+                        None => return Ok(None),
+                    };
+
+                    // The current segment may have been completed before the
+                    // next one was created
+                    if let Some(line) = self.read_line()? {
+                        line
+                    } else {
+                        self.open_segment(next_segment)?;
+                        continue;
+                    }
+                }
+            };
+
+            match serde_json::from_str::<Entry>(&line) {
+                Ok(entry) if entry.id > self.after => {
+                    self.after = entry.id;
+                    return Ok(Some(entry));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping an invalid outbox entry: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::csp_report_content::CspReportContent;
+
+    fn report(blocked_uri: &str) -> ReceivedReport {
+        ReceivedReport::new(CspReportContent::default(blocked_uri, "", "", "", ""))
+    }
+
+    fn read_all(reader: &mut Reader) -> Vec<u64> {
+        let mut ids = vec![];
+        while let Some(entry) = reader.next().unwrap() {
+            ids.push(entry.id);
+        }
+        ids
+    }
+
+    #[test]
+    fn it_reads_appended_reports_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path()).unwrap();
+        outbox.segment_size = 1;
+
+        let mut reader = outbox.reader(0);
+        assert!(reader.next().unwrap().is_none());
+
+        for _ in 0..3 {
+            outbox.append(report("https://a.example.com")).unwrap();
+        }
+        assert_eq!(vec![1, 2, 3], read_all(&mut reader));
+        assert_eq!(3, segments(dir.path()).unwrap().len());
+
+        outbox.append(report("https://b.example.com")).unwrap();
+        let entry = reader.next().unwrap().unwrap();
+        assert_eq!(4, entry.id);
+        assert_eq!("https://b.example.com", entry.report.csp_report.blocked_uri);
+
+        assert_eq!(vec![3, 4], read_all(&mut outbox.reader(2)));
+    }
+
+    #[test]
+    fn it_resumes_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).unwrap();
+        outbox.append(report("https://a.example.com")).unwrap();
+        outbox.append(report("https://a.example.com")).unwrap();
+        outbox.commit("mail", 1).unwrap();
+        drop(outbox);
+
+        // A crash while appending leaves a partial line
+        let segment = segment_path(dir.path(), 1);
+        append_line(&segment, b"{\"id\":3,").unwrap();
+
+        let outbox = Outbox::open(dir.path()).unwrap();
+        assert_eq!(2, outbox.last_id());
+        assert_eq!(3, outbox.append(report("https://a.example.com")).unwrap());
+
+        let cursor = outbox.cursor("mail").unwrap().unwrap();
+        assert_eq!(vec![2, 3], read_all(&mut outbox.reader(cursor)));
+        assert_eq!(vec![String::from("mail")], queues(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn it_removes_handled_segments_and_keeps_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path()).unwrap();
+        outbox.segment_size = 1;
+
+        for _ in 0..3 {
+            outbox.append(report("https://a.example.com")).unwrap();
+        }
+        let queues = vec![String::from("mail"), String::from("sentry")];
+        outbox.commit("mail", 3).unwrap();
+        outbox.commit("sentry", 1).unwrap();
+
+        assert_eq!(1, outbox.compact(&queues).unwrap());
+        assert_eq!(vec![2, 3], read_all(&mut outbox.reader(1)));
+
+        let entry = outbox.reader(1).next().unwrap().unwrap();
+        outbox
+            .dead_letter("sentry", &entry, &Error::msg("invalid DSN"))
+            .unwrap();
+
+        let dead_letters = dead_letters(dir.path(), "sentry").unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!(2, dead_letters[0].id);
+        assert_eq!("invalid DSN", dead_letters[0].error);
+        assert_eq!(
+            vec![String::from("sentry")],
+            dead_letter_queues(dir.path()).unwrap()
+        );
+    }
+}
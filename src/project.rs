@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Error};
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+
+#[cfg(feature = "slack")]
+use crate::channel::chat::slack::Slack;
+#[cfg(any(feature = "discord", feature = "slack", feature = "teams"))]
+use crate::channel::chat::{webhook_url, ChatWebhook};
+#[cfg(feature = "mail")]
+use crate::channel::mailer::routing::{Recipient, Recipients};
+#[cfg(feature = "mail")]
+use crate::channel::mailer::Mailer;
+#[cfg(feature = "sentry")]
+use crate::channel::sentry::Sentry;
+#[cfg(feature = "webhook")]
+use crate::channel::webhook::{parse_url, Webhook};
+use crate::config::{self, ConfigFormat};
+use crate::csp::rules::FilterRules;
+use crate::reload::SharedFilterRules;
+
+/// The projects file, as written by the user
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectsFile {
+    #[serde(default)]
+    projects: HashMap<String, ProjectConfig>,
+}
+
+/// A project, as written in the projects file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectConfig {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+
+    /// Rules file of the project, defaults to the global filter rules
+    filters: Option<PathBuf>,
+
+    #[cfg(feature = "discord")]
+    discord: Option<ProjectChat>,
+
+    #[cfg(feature = "mail")]
+    mail: Option<ProjectMail>,
+
+    #[cfg(feature = "sentry")]
+    sentry: Option<ProjectSentry>,
+
+    #[cfg(feature = "slack")]
+    slack: Option<ProjectSlack>,
+
+    #[cfg(feature = "teams")]
+    teams: Option<ProjectChat>,
+
+    #[cfg(feature = "webhook")]
+    webhook: Option<ProjectWebhook>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Mail channel settings overridden by a project
+#[cfg(feature = "mail")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectMail {
+    pub to_name: Option<String>,
+    pub to_email: Option<String>,
+
+    #[serde(default)]
+    pub to: Vec<Recipient>,
+    #[serde(default)]
+    pub cc: Vec<Recipient>,
+    #[serde(default)]
+    pub bcc: Vec<Recipient>,
+}
+
+#[cfg(feature = "mail")]
+impl ProjectMail {
+    /// Replace the default recipients of the mailer with the ones of the
+    /// project
+    pub fn apply(&self, mailer: &mut Mailer) -> Result<(), Error> {
+        let mut recipients = Recipients {
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+        };
+        if let Some(email) = &self.to_email {
+            let recipient = email.parse::<Recipient>().map_err(Error::msg)?;
+            recipients.to.insert(
+                0,
+                Recipient {
+                    name: self.to_name.clone(),
+                    ..recipient
+                },
+            );
+        }
+
+        if recipients.is_empty() {
+            bail!("Project mail settings have no recipient");
+        }
+        mailer.recipients = recipients;
+
+        Ok(())
+    }
+}
+
+/// Sentry channel settings overridden by a project
+#[cfg(feature = "sentry")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectSentry {
+    pub dsn: String,
+}
+
+#[cfg(feature = "sentry")]
+impl ProjectSentry {
+    pub fn apply(&self, sentry: &mut Sentry) -> Result<(), Error> {
+        Sentry::validate_dsn(&self.dsn)?;
+        sentry.dsn = self.dsn.clone();
+
+        Ok(())
+    }
+}
+
+/// Post the messages of a chat channel to another webhook
+#[cfg(any(feature = "discord", feature = "slack", feature = "teams"))]
+fn set_webhook_url(webhook: &mut ChatWebhook, url: &str) -> Result<(), Error> {
+    webhook.url = webhook_url(url).context("Invalid webhook_url")?;
+
+    Ok(())
+}
+
+/// Teams or Discord channel settings overridden by a project
+#[cfg(any(feature = "discord", feature = "teams"))]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectChat {
+    pub webhook_url: String,
+}
+
+#[cfg(any(feature = "discord", feature = "teams"))]
+impl ProjectChat {
+    pub fn apply(&self, webhook: &mut ChatWebhook) -> Result<(), Error> {
+        set_webhook_url(webhook, &self.webhook_url)
+    }
+}
+
+/// Slack channel settings overridden by a project, to post its reports in
+/// its own room
+#[cfg(feature = "slack")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectSlack {
+    pub webhook_url: Option<String>,
+    pub channel: Option<String>,
+}
+
+#[cfg(feature = "slack")]
+impl ProjectSlack {
+    pub fn apply(&self, slack: &mut Slack) -> Result<(), Error> {
+        if let Some(url) = &self.webhook_url {
+            set_webhook_url(&mut slack.webhook, url)?;
+        }
+        if self.channel.is_some() {
+            slack.channel = self.channel.clone();
+        }
+
+        Ok(())
+    }
+}
+
+/// Webhook channel settings overridden by a project, to send its reports
+/// to its own receivers
+#[cfg(feature = "webhook")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectWebhook {
+    pub urls: Option<Vec<String>>,
+    pub secret: Option<String>,
+}
+
+#[cfg(feature = "webhook")]
+impl ProjectWebhook {
+    pub fn apply(&self, webhook: &mut Webhook) -> Result<(), Error> {
+        if let Some(urls) = &self.urls {
+            if urls.is_empty() {
+                bail!("urls cannot be empty");
+            }
+            webhook.urls = urls
+                .iter()
+                .map(|url| parse_url(url).context("Invalid urls"))
+                .collect::<Result<_, _>>()?;
+        }
+        if self.secret.is_some() {
+            webhook.secret = self.secret.clone();
+        }
+
+        Ok(())
+    }
+}
+
+/// A site whose reports are received on its own endpoint, with its own
+/// filters and channels
+#[derive(Debug)]
+pub struct Project {
+    pub slug: String,
+    pub enabled: bool,
+
+    /// The rules file of the project, if it does not use the global one
+    pub filters: Option<PathBuf>,
+    pub filter_rules: SharedFilterRules,
+
+    #[cfg(feature = "discord")]
+    pub discord: Option<ProjectChat>,
+
+    #[cfg(feature = "mail")]
+    pub mail: Option<ProjectMail>,
+
+    #[cfg(feature = "sentry")]
+    pub sentry: Option<ProjectSentry>,
+
+    #[cfg(feature = "slack")]
+    pub slack: Option<ProjectSlack>,
+
+    #[cfg(feature = "teams")]
+    pub teams: Option<ProjectChat>,
+
+    #[cfg(feature = "webhook")]
+    pub webhook: Option<ProjectWebhook>,
+}
+
+/// The projects, by slug
+#[derive(Debug, Default)]
+pub struct Projects {
+    projects: HashMap<String, Project>,
+}
+
+/// Test if a slug can be used in an URL path
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Projects {
+    /// Load the projects from a TOML or YAML file. Rules files are relative
+    /// to the projects file, and projects without rules file share the
+    /// global filter rules.
+    pub fn load(path: &Path, global_rules: &SharedFilterRules) -> Result<Self, Error> {
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        config::load(path, |content, format| {
+            Self::parse(content, format, base_dir, global_rules)
+        })
+    }
+
+    /// Parse the content of a projects file
+    pub fn parse(
+        content: &str,
+        format: ConfigFormat,
+        base_dir: &Path,
+        global_rules: &SharedFilterRules,
+    ) -> Result<Self, Error> {
+        let file: ProjectsFile = format.parse(content)?;
+        let mut projects = HashMap::new();
+
+        for (slug, config) in file.projects {
+            if !is_valid_slug(&slug) {
+                bail!(
+                    "Invalid project slug {:?}, only lowercase letters, digits, - and _ are allowed",
+                    slug
+                );
+            }
+
+            let filters = config.filters.map(|filters| base_dir.join(filters));
+            let filter_rules = match &filters {
+                Some(filters) => Arc::new(ArcSwap::from_pointee(
+                    FilterRules::load(filters)
+                        .with_context(|| format!("Invalid filters of project {}", slug))?,
+                )),
+                None => global_rules.clone(),
+            };
+
+            let project = Project {
+                slug: slug.clone(),
+                enabled: config.enabled,
+                filters,
+                filter_rules,
+                #[cfg(feature = "discord")]
+                discord: config.discord,
+                #[cfg(feature = "mail")]
+                mail: config.mail,
+                #[cfg(feature = "sentry")]
+                sentry: config.sentry,
+                #[cfg(feature = "slack")]
+                slack: config.slack,
+                #[cfg(feature = "teams")]
+                teams: config.teams,
+                #[cfg(feature = "webhook")]
+                webhook: config.webhook,
+            };
+
+            projects.insert(slug, project);
+        }
+
+        Ok(Projects { projects })
+    }
+
+    /// Find a project by slug
+    pub fn get(&self, slug: &str) -> Option<&Project> {
+        self.projects.get(slug)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Project> {
+        self.projects.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn global_rules() -> SharedFilterRules {
+        Arc::new(ArcSwap::from_pointee(FilterRules::default()))
+    }
+
+    #[test]
+    fn it_reads_projects() {
+        let mut rules_file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(rules_file, "defaults = false").unwrap();
+
+        let content = format!(
+            r#"
+            [projects.checkout]
+            filters = "{}"
+
+            [projects.blog]
+            enabled = false
+            "#,
+            rules_file.path().file_name().unwrap().to_str().unwrap()
+        );
+
+        let global_rules = global_rules();
+        let projects = Projects::parse(
+            &content,
+            ConfigFormat::Toml,
+            rules_file.path().parent().unwrap(),
+            &global_rules,
+        )
+        .unwrap();
+
+        let checkout = projects.get("checkout").unwrap();
+        assert!(checkout.enabled);
+        assert!(checkout.filter_rules.load().blocked_uri.is_empty());
+
+        let blog = projects.get("blog").unwrap();
+        assert!(!blog.enabled);
+        assert!(Arc::ptr_eq(&global_rules, &blog.filter_rules));
+
+        assert!(projects.get("shop").is_none());
+    }
+
+    #[test]
+    fn it_rejects_invalid_slugs() {
+        let projects = Projects::parse(
+            "[projects.\"Check/Out\"]",
+            ConfigFormat::Toml,
+            Path::new(""),
+            &global_rules(),
+        );
+        assert!(projects.is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Error;
+use arc_swap::ArcSwap;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::csp::rules::FilterRules;
+
+/// Filter rules shared by the request handlers, swapped atomically on reload
+pub type SharedFilterRules = Arc<ArcSwap<FilterRules>>;
+
+/// Load a rules file and swap it with the current rules. On error, the
+/// current rules are kept.
+pub fn reload(path: &Path, rules: &SharedFilterRules) -> Result<(), Error> {
+    let new_rules = FilterRules::load(path)?;
+    rules.store(Arc::new(new_rules));
+
+    Ok(())
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reload the rules file on SIGHUP, or when its modification time changes
+pub fn spawn_reloader(path: PathBuf, rules: SharedFilterRules, interval: Duration) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Unable to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        let mut ticker = tokio::time::interval(interval);
+        let mut last_modified_at = modified_at(&path);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading {}", path.display());
+                }
+                _ = ticker.tick() => {
+                    let modified_at = modified_at(&path);
+
+                    if modified_at == last_modified_at {
+                        continue;
+                    }
+
+                    last_modified_at = modified_at;
+                    info!("{} changed, reloading", path.display());
+                }
+            }
+
+            match reload(&path, &rules) {
+                Ok(()) => info!("Filter rules reloaded from {}", path.display()),
+                Err(e) => error!("{:#}, keeping the previous filter rules", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn it_swaps_valid_rules() {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(file, "defaults = false").unwrap();
+
+        let rules: SharedFilterRules = Arc::new(ArcSwap::from_pointee(FilterRules::default()));
+        reload(file.path(), &rules).unwrap();
+
+        assert!(rules.load().blocked_uri.is_empty());
+    }
+
+    #[test]
+    fn it_keeps_the_previous_rules_on_error() {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(file, "defaults = ").unwrap();
+
+        let rules: SharedFilterRules = Arc::new(ArcSwap::from_pointee(FilterRules::default()));
+        assert!(reload(file.path(), &rules).is_err());
+
+        assert!(!rules.load().blocked_uri.is_empty());
+    }
+}
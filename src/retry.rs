@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How many times, and how long to wait before, a channel retries to send a
+/// report
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled on each retry
+    pub initial_delay: Duration,
+
+    /// Maximum delay between two retries
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before a retry (starting at 1): exponential backoff capped
+    /// to the maximum delay, of which a random half is kept so that the
+    /// retries of several channels do not happen at the same time
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let policy = RetryPolicy {
+            retries: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        let first = policy.delay(1);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+        let third = policy.delay(3);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+
+        let capped = policy.delay(10);
+        assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_secs(1));
+    }
+}